@@ -0,0 +1,4 @@
+ALTER TABLE rules ADD COLUMN icmp BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN icmpv6 BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN icmp_type INTEGER NOT NULL DEFAULT 255;
+ALTER TABLE rules ADD COLUMN icmp_code INTEGER NOT NULL DEFAULT 255;
@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN established BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+ALTER TABLE rules ADD COLUMN source_set TEXT NOT NULL DEFAULT '';
+ALTER TABLE rules ADD COLUMN destination_set TEXT NOT NULL DEFAULT '';
+
+CREATE TABLE IF NOT EXISTS ip_sets (
+     id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+     set_name                TEXT NOT NULL UNIQUE,
+     entries                 TEXT NOT NULL
+);
@@ -0,0 +1,54 @@
+-- Раньше входящий траффик сверялся только с источником, исходящий - только с назначением.
+-- Теперь правило сверяется целиком, поэтому приводим старые правила к прежнему поведению.
+
+-- Правила на оба направления с заполненными источником и назначением делим на два
+INSERT INTO rules (
+     rule_name, "drop", ok, v4, v6, tcp, udp, icmp, icmpv6, "on",
+     source_addr_v6, destination_addr_v6, source_addr_v4, destination_addr_v4,
+     ifindex, "order",
+     source_port_start, source_port_end, destination_port_start, destination_port_end,
+     input, output,
+     source_mask_v4, destination_mask_v4, source_mask_v6, destination_mask_v6,
+     icmp_type, icmp_code, established, source_set, destination_set
+)
+SELECT
+     rule_name, "drop", ok, v4, v6, tcp, udp, icmp, icmpv6, "on",
+     '::', destination_addr_v6, 0, destination_addr_v4,
+     ifindex, "order",
+     0, 0, destination_port_start, destination_port_end,
+     0, 1,
+     0, destination_mask_v4, 0, destination_mask_v6,
+     icmp_type, icmp_code, established, '', destination_set
+FROM rules
+WHERE input = 1 AND output = 1
+  AND (source_addr_v4 != 0 OR source_addr_v6 != '::' OR source_port_start != 0 OR source_port_end != 0 OR source_set != '')
+  AND (destination_addr_v4 != 0 OR destination_addr_v6 != '::' OR destination_port_start != 0 OR destination_port_end != 0 OR destination_set != '');
+
+UPDATE rules SET output = 0
+WHERE input = 1 AND output = 1
+  AND (source_addr_v4 != 0 OR source_addr_v6 != '::' OR source_port_start != 0 OR source_port_end != 0 OR source_set != '')
+  AND (destination_addr_v4 != 0 OR destination_addr_v6 != '::' OR destination_port_start != 0 OR destination_port_end != 0 OR destination_set != '');
+
+-- Заполнена только одна сторона - правило срабатывало только в одном направлении
+UPDATE rules SET output = 0
+WHERE input = 1 AND output = 1
+  AND (source_addr_v4 != 0 OR source_addr_v6 != '::' OR source_port_start != 0 OR source_port_end != 0 OR source_set != '');
+
+UPDATE rules SET input = 0
+WHERE input = 1 AND output = 1
+  AND (destination_addr_v4 != 0 OR destination_addr_v6 != '::' OR destination_port_start != 0 OR destination_port_end != 0 OR destination_set != '');
+
+-- Поля, которые раньше игнорировались
+UPDATE rules SET
+     destination_addr_v4 = 0, destination_mask_v4 = 0,
+     destination_addr_v6 = '::', destination_mask_v6 = 0,
+     destination_port_start = 0, destination_port_end = 0,
+     destination_set = ''
+WHERE input = 1 AND output = 0;
+
+UPDATE rules SET
+     source_addr_v4 = 0, source_mask_v4 = 0,
+     source_addr_v6 = '::', source_mask_v6 = 0,
+     source_port_start = 0, source_port_end = 0,
+     source_set = ''
+WHERE input = 0 AND output = 1;
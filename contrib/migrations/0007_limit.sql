@@ -0,0 +1,5 @@
+ALTER TABLE rules ADD COLUMN "limit" BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN limit_rate INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN limit_burst INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN limit_bytes BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN limit_per_source BOOLEAN NOT NULL DEFAULT 0;
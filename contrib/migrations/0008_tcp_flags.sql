@@ -0,0 +1,2 @@
+ALTER TABLE rules ADD COLUMN tcp_flags INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN tcp_flags_mask INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN reject BOOLEAN NOT NULL DEFAULT 0;
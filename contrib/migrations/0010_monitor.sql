@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN monitor BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN vlan_id INTEGER NOT NULL DEFAULT 0;
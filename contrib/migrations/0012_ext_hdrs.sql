@@ -0,0 +1,2 @@
+ALTER TABLE rules ADD COLUMN ext_hdrs INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN ext_hdrs_mask INTEGER NOT NULL DEFAULT 0;
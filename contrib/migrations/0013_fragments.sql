@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN fragments BOOLEAN NOT NULL DEFAULT 0;
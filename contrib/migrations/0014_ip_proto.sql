@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN ip_proto INTEGER NOT NULL DEFAULT 0;
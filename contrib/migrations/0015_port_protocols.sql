@@ -0,0 +1,3 @@
+ALTER TABLE rules ADD COLUMN sctp BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN dccp BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE rules ADD COLUMN udplite BOOLEAN NOT NULL DEFAULT 0;
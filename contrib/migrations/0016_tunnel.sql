@@ -0,0 +1 @@
+ALTER TABLE rules ADD COLUMN tunnel INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,220 @@
+use crate::rules::Action;
+
+// Значение не задано - берётся из политики "*" или встроенной по умолчанию
+pub const POLICY_UNSET: u8 = 0;
+// Только для fragments: остальные фрагменты получают вердикт первого
+pub const POLICY_TRACK: u8 = 0x10;
+
+// Тип канального уровня интерфейса, выставляет загрузчик при подключении программ
+pub const LINK_ETHERNET: u8 = 0;
+// Пакет начинается сразу с IP заголовка (WireGuard, tun, PPP, IP туннели)
+pub const LINK_RAW_IP: u8 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub unmatched: u8,
+    pub unhandled: u8,
+    pub non_ip: u8,
+    // Цепочка заголовков расширения IPv6 слишком длинная, до L4 не дошли
+    pub ext_too_deep: u8,
+    pub fragments: u8,
+    pub _pad: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IfaceConfig {
+    pub input: Policy,
+    pub output: Policy,
+    pub link_type: u8,
+    // Разбирать внутренние пакеты туннелей (VXLAN, Geneve, GRE, IPIP)
+    pub decap: bool,
+    pub _pad: [u8; 2],
+}
+
+// Выключаемые возможности, биты GlobalConfig.disabled
+// Счётчики срабатываний правил (RULES_STATS)
+pub const FEATURE_RULE_STATS: u32 = 0x01;
+// Отслеживание соединений для established
+pub const FEATURE_CONNTRACK: u32 = 0x02;
+// Разбор туннелей, вместе с decap интерфейса
+pub const FEATURE_DECAP: u32 = 0x04;
+
+// Настройки всей программы, единственная запись GLOBAL_CONFIG. loader меняет её на лету,
+// нулевая запись (map ещё не заполнена) означает поведение по умолчанию
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct GlobalConfig {
+    // События ниже этого уровня (DEBUG..ERROR) не пишутся в ring buffer, MONITOR пишется всегда
+    pub log_level: u8,
+    // Вердикт для пакета, L2 заголовок которого не разобран (например, короче Ethernet заголовка)
+    pub malformed: u8,
+    pub _pad: [u8; 2],
+    // В лог попадает одно из N событий DEBUG и INFO, 0 и 1 - все
+    pub log_sample_rate: u32,
+    // FEATURE_*
+    pub disabled: u32,
+}
+
+impl GlobalConfig {
+    pub const fn empty() -> Self {
+        Self {
+            log_level: 0,
+            malformed: POLICY_UNSET,
+            _pad: [0; 2],
+            log_sample_rate: 0,
+            disabled: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self, feature: u32) -> bool {
+        self.disabled & feature == 0
+    }
+}
+
+impl Policy {
+    pub const DEFAULT: Policy = Policy {
+        unmatched: Action::Ok as u8,
+        unhandled: Action::Drop as u8,
+        non_ip: Action::Ok as u8,
+        ext_too_deep: Action::Drop as u8,
+        fragments: POLICY_TRACK,
+        _pad: [0; 3],
+    };
+
+    pub const fn empty() -> Self {
+        Self {
+            unmatched: POLICY_UNSET,
+            unhandled: POLICY_UNSET,
+            non_ip: POLICY_UNSET,
+            ext_too_deep: POLICY_UNSET,
+            fragments: POLICY_UNSET,
+            _pad: [0; 3],
+        }
+    }
+
+    #[inline(always)]
+    pub fn merge(&mut self, other: &Policy) {
+        if other.unmatched != POLICY_UNSET {
+            self.unmatched = other.unmatched;
+        }
+        if other.unhandled != POLICY_UNSET {
+            self.unhandled = other.unhandled;
+        }
+        if other.non_ip != POLICY_UNSET {
+            self.non_ip = other.non_ip;
+        }
+        if other.ext_too_deep != POLICY_UNSET {
+            self.ext_too_deep = other.ext_too_deep;
+        }
+        if other.fragments != POLICY_UNSET {
+            self.fragments = other.fragments;
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_drop(verdict: u8) -> bool {
+        verdict == Action::Drop as u8
+    }
+}
+
+impl IfaceConfig {
+    pub const fn empty() -> Self {
+        Self {
+            input: Policy::empty(),
+            output: Policy::empty(),
+            link_type: LINK_ETHERNET,
+            decap: false,
+            _pad: [0; 2],
+        }
+    }
+
+    #[inline(always)]
+    pub fn policy(&self, input: bool) -> &Policy {
+        if input { &self.input } else { &self.output }
+    }
+}
+
+#[cfg(feature = "user")]
+pub mod config {
+    use crate::config::{
+        FEATURE_CONNTRACK, FEATURE_DECAP, FEATURE_RULE_STATS, GlobalConfig, IfaceConfig,
+        POLICY_TRACK, POLICY_UNSET, Policy,
+    };
+    use crate::logs::{DEBUG, ERROR, INFO, WARN};
+    use crate::rules::Action;
+    use yaml_rust2::Yaml;
+
+    unsafe impl aya::Pod for IfaceConfig {}
+    unsafe impl aya::Pod for GlobalConfig {}
+
+    // Ошибка - текст для лога, опечатка в main.yaml не должна ронять loader
+    fn parse_verdict(yaml: &Yaml) -> Result<u8, String> {
+        match yaml.as_str() {
+            Some("ok") | Some("pass") | Some("accept") => Ok(Action::Ok as u8),
+            Some("drop") => Ok(Action::Drop as u8),
+            Some("track") => Ok(POLICY_TRACK),
+            Some(verdict) => Err(format!("Unknown policy verdict: {}", verdict)),
+            None => Ok(POLICY_UNSET),
+        }
+    }
+
+    impl Policy {
+        pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+            Ok(Self {
+                unmatched: parse_verdict(&yaml["unmatched"])?,
+                unhandled: parse_verdict(&yaml["unhandled"])?,
+                non_ip: parse_verdict(&yaml["non_ip"])?,
+                ext_too_deep: parse_verdict(&yaml["ext_too_deep"])?,
+                fragments: parse_verdict(&yaml["fragments"])?,
+                _pad: [0; 3],
+            })
+        }
+    }
+
+    impl IfaceConfig {
+        pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+            Ok(Self {
+                input: Policy::from_yaml(&yaml["input"])?,
+                output: Policy::from_yaml(&yaml["output"])?,
+                decap: yaml["decap"].as_bool().unwrap_or(false),
+                ..IfaceConfig::empty()
+            })
+        }
+    }
+
+    impl GlobalConfig {
+        pub fn from_yaml(yaml: &Yaml) -> Result<Self, String> {
+            let log_level = match yaml["log_level"].as_str() {
+                Some("debug") | None => DEBUG,
+                Some("info") => INFO,
+                Some("warn") => WARN,
+                Some("error") => ERROR,
+                Some(level) => return Err(format!("Unknown log level: {}", level)),
+            };
+            let malformed = parse_verdict(&yaml["malformed"])?;
+            if malformed == POLICY_TRACK {
+                return Err("Verdict track is only for fragments".to_string());
+            }
+            let mut disabled = 0;
+            for (name, feature) in [
+                ("rule_stats", FEATURE_RULE_STATS),
+                ("conntrack", FEATURE_CONNTRACK),
+                ("decap", FEATURE_DECAP),
+            ] {
+                if !yaml[name].as_bool().unwrap_or(true) {
+                    disabled |= feature;
+                }
+            }
+            Ok(Self {
+                log_level,
+                malformed,
+                log_sample_rate: yaml["log_sample_rate"].as_i64().unwrap_or(1) as u32,
+                disabled,
+                ..GlobalConfig::empty()
+            })
+        }
+    }
+}
@@ -1,4 +1,7 @@
-pub fn from_u8_to_str(proto: &u8) -> &str {
+// Правило без ip_proto: протокол задаётся флагами tcp / udp / icmp / icmpv6
+pub const IP_PROTO_ANY: u8 = 0;
+
+pub fn from_u8_to_str(proto: &u8) -> &'static str {
     match proto {
         0 => "HopOpt",
         1 => "Icmp",
@@ -151,3 +154,13 @@ pub fn from_u8_to_str(proto: &u8) -> &str {
         _ => "Unknown",
     }
 }
+
+/// Номер протокола по имени из таблицы выше (без учёта регистра) или привычному сокращению.
+pub fn from_str_to_u8(name: &str) -> Option<u8> {
+    if name.eq_ignore_ascii_case("ospf") {
+        return Some(89);
+    }
+    (1..=254u8)
+        .filter(|proto| from_u8_to_str(proto) != "Unknown")
+        .find(|proto| from_u8_to_str(proto).eq_ignore_ascii_case(name))
+}
@@ -1,4 +1,9 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod config;
+pub mod ipproto;
+pub mod limits;
 pub mod logs;
 pub mod rules;
+pub mod sets;
+pub mod tunnel;
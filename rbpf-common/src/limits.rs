@@ -0,0 +1,145 @@
+use crate::rules::Rule;
+
+// Токены храним в миллиардных долях, чтобы пополнять корзину по наносекундам без деления
+pub const TOKEN: u64 = 1_000_000_000;
+
+// Корзина token bucket правила limit, токены в миллиардных долях
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub tokens: u64,
+    pub last_ns: u64,
+}
+
+// Ёмкость корзины, burst 0 - равен скорости
+#[inline(always)]
+pub fn capacity(rule: &Rule) -> u64 {
+    let burst = if rule.limit_burst == 0 {
+        rule.limit_rate
+    } else {
+        rule.limit_burst
+    };
+    burst as u64 * TOKEN
+}
+
+// Сколько токенов стоит пакет длиной len
+#[inline(always)]
+pub fn cost(rule: &Rule, len: u32) -> u64 {
+    if rule.limit_bytes {
+        len as u64 * TOKEN
+    } else {
+        TOKEN
+    }
+}
+
+impl Bucket {
+    /// Новая корзина, полная за вычетом первого пакета. None - пакет больше корзины.
+    #[inline(always)]
+    pub fn first(capacity: u64, cost: u64, now: u64) -> Option<Self> {
+        if capacity < cost {
+            return None;
+        }
+        Some(Self {
+            tokens: capacity - cost,
+            last_ns: now,
+        })
+    }
+
+    /// Пополняет корзину за прошедшее время со скоростью rate в секунду и берёт cost.
+    /// true - токенов не хватило, пакет сверх лимита.
+    #[inline(always)]
+    pub fn take(&mut self, rate: u64, capacity: u64, cost: u64, now: u64) -> bool {
+        // rate токенов в секунду = rate миллиардных долей токена в наносекунду
+        let elapsed = now.saturating_sub(self.last_ns);
+        let tokens = self
+            .tokens
+            .saturating_add(elapsed.saturating_mul(rate))
+            .min(capacity);
+        self.last_ns = now;
+        if tokens < cost {
+            self.tokens = tokens;
+            return true;
+        }
+        self.tokens = tokens - cost;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn burst_then_over_limit() {
+        // 10 пакетов в секунду, burst 3
+        let capacity = 3 * TOKEN;
+        let mut bucket = Bucket::first(capacity, TOKEN, 0).unwrap();
+        assert!(!bucket.take(10, capacity, TOKEN, 0));
+        assert!(!bucket.take(10, capacity, TOKEN, 0));
+        assert!(bucket.take(10, capacity, TOKEN, 0));
+        assert_eq!(bucket.tokens, 0);
+    }
+
+    #[test]
+    fn refill_by_elapsed_time() {
+        let capacity = 10 * TOKEN;
+        let mut bucket = Bucket {
+            tokens: 0,
+            last_ns: 0,
+        };
+        // За 100 мс при 10 пакетах в секунду набегает ровно один токен
+        assert!(!bucket.take(10, capacity, TOKEN, SECOND / 10));
+        assert_eq!(bucket.tokens, 0);
+        // Через 99 мс токена ещё нет, недобранная доля сохраняется
+        assert!(bucket.take(10, capacity, TOKEN, SECOND / 10 + 99_000_000));
+        assert_eq!(bucket.tokens, 990_000_000);
+        assert!(!bucket.take(10, capacity, TOKEN, SECOND / 10 + 100_000_000));
+    }
+
+    #[test]
+    fn refill_is_capped_by_burst() {
+        let capacity = 5 * TOKEN;
+        let mut bucket = Bucket {
+            tokens: 0,
+            last_ns: 0,
+        };
+        assert!(!bucket.take(100, capacity, TOKEN, 3600 * SECOND));
+        assert_eq!(bucket.tokens, 4 * TOKEN);
+    }
+
+    #[test]
+    fn clock_going_back_does_not_refill() {
+        let mut bucket = Bucket {
+            tokens: 0,
+            last_ns: 10 * SECOND,
+        };
+        assert!(bucket.take(10, TOKEN, TOKEN, SECOND));
+        assert_eq!(bucket.tokens, 0);
+    }
+
+    #[test]
+    fn nano_token_conversion() {
+        // Все поля Rule - числа и bool, нулевое значение корректно
+        let mut rule: Rule = unsafe { core::mem::zeroed() };
+        rule.limit_rate = 1000;
+        assert_eq!(capacity(&rule), 1000 * TOKEN);
+        rule.limit_burst = 1500;
+        assert_eq!(capacity(&rule), 1500 * TOKEN);
+        assert_eq!(cost(&rule, 1400), TOKEN);
+        rule.limit_bytes = true;
+        assert_eq!(cost(&rule, 1400), 1400 * TOKEN);
+        // Максимальные u32 не переполняют u64
+        rule.limit_burst = u32::MAX;
+        assert_eq!(capacity(&rule), u32::MAX as u64 * TOKEN);
+    }
+
+    #[test]
+    fn packet_larger_than_bucket() {
+        assert_eq!(Bucket::first(1000 * TOKEN, 1500 * TOKEN, 0), None);
+        let bucket = Bucket::first(1500 * TOKEN, 1500 * TOKEN, 7).unwrap();
+        assert_eq!(bucket.tokens, 0);
+        assert_eq!(bucket.last_ns, 7);
+    }
+}
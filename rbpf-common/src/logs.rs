@@ -1,8 +1,12 @@
+use crate::tunnel::InnerTuple;
+
 // Костыль что бы не заморачиваться с передачей enum eBPF -> userspace
 pub const DEBUG: u8 = 0;
 pub const INFO: u8 = 1;
 pub const WARN: u8 = 2;
 pub const ERROR: u8 = 3;
+// Срабатывание правила в режиме monitor
+pub const MONITOR: u8 = 4;
 
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
@@ -13,6 +17,10 @@ pub struct LogMessage {
     pub v4: bool,
     pub tcp: bool,
     pub udp: bool,
+    pub icmp: bool,
+    pub sctp: bool,
+    pub dccp: bool,
+    pub udplite: bool,
 
     pub src_ip_high: u64,
     pub src_ip_low: u64,
@@ -27,14 +35,23 @@ pub struct LogMessage {
 
     pub source_port: u16,
     pub destination_port: u16,
+    pub vlan_id: u16,
+
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub tcp_flags: u8,
 
     pub level: u8,
     pub timestamp: u64,
+
+    // kind == TUNNEL_NONE - пакет не из туннеля
+    pub inner: InnerTuple,
 }
 
 #[cfg(feature = "user")]
 pub mod logs {
     extern crate alloc;
+    use crate::tunnel::tunnel::InnerTupleSerialized;
     use alloc::string::String;
     use core::net::{Ipv4Addr, Ipv6Addr};
     use serde::{Deserialize, Serialize};
@@ -52,6 +69,9 @@ pub mod logs {
         Ok = 0,
         Drop = 1,
         Pipe = 2,
+        Limit = 3,
+        Reject = 4,
+        Monitor = 5,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,6 +84,13 @@ pub mod logs {
     pub enum ProtocolType {
         TCP = 0,
         UDP = 1,
+        ICMP = 2,
+        ICMPv6 = 3,
+        SCTP = 4,
+        DCCP = 5,
+        UDPLite = 6,
+        // Протокол без разбора L4, номер есть в текстовом логе
+        Other = 7,
     }
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -83,9 +110,17 @@ pub mod logs {
 
         pub source_port: u16,
         pub destination_port: u16,
+        pub vlan_id: u16,
+
+        pub icmp_type: u8,
+        pub icmp_code: u8,
+        pub tcp_flags: u8,
 
         pub level: u8,
         pub action: ActionType,
         pub timestamp: u64,
+
+        // Внутренний пакет туннеля, если включён decap
+        pub inner: Option<InnerTupleSerialized>,
     }
 }
@@ -1,5 +1,5 @@
 #[repr(C, align(8))]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Rule {
     pub drop: bool,
     pub ok: bool,
@@ -7,17 +7,17 @@ pub struct Rule {
     pub v6: bool,
     pub tcp: bool,
     pub udp: bool,
+    pub icmp: bool,
+    pub icmpv6: bool,
     pub on: bool,
     pub input: bool,
     pub output: bool,
-
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub _reserved: bool,
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub _pad1: [u8; 6],
-
-    #[cfg(target_arch = "arm")]
-    pub _pad1: [u8; 5],
+    pub established: bool,
+    pub limit: bool,
+    pub reject: bool,
+    pub monitor: bool,
+    // Правило только для фрагментированных пакетов
+    pub fragments: bool,
 
     pub source_addr_v6: u128,
     pub destination_addr_v6: u128,
@@ -26,19 +26,132 @@ pub struct Rule {
     pub destination_addr_v4: u32,
     pub rule_id: u32,
     pub ifindex: u32,
+    pub source_set_id: u32,
+    pub destination_set_id: u32,
+
+    // Скорость в пакетах (или байтах) в секунду и размер корзины, 0 - равен скорости
+    pub limit_rate: u32,
+    pub limit_burst: u32,
 
     pub source_port_start: u16,
     pub source_port_end: u16,
     pub destination_port_start: u16,
     pub destination_port_end: u16,
 
+    // 0 - любой VLAN (и пакеты без тега)
+    pub vlan_id: u16,
+
     pub source_mask_v4: u8,
     pub destination_mask_v4: u8,
     pub source_mask_v6: u8,
     pub destination_mask_v6: u8,
 
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+
+    pub limit_bytes: bool,
+    pub limit_per_source: bool,
+
+    // Пакет подходит, если (флаги & tcp_flags_mask) == tcp_flags, маска 0 - флаги не проверяются
+    pub tcp_flags: u8,
+    pub tcp_flags_mask: u8,
+
+    // Заголовки расширения IPv6 (EXT_*), проверяются так же как TCP флаги
+    pub ext_hdrs: u8,
+    pub ext_hdrs_mask: u8,
+
+    // Номер IP протокола (IPPROTO_*), 0 - протокол задаётся флагами tcp / udp / icmp
+    pub ip_proto: u8,
+
+    pub sctp: bool,
+    pub dccp: bool,
+    pub udplite: bool,
+
+    // TUNNEL_OUTER / TUNNEL_INNER / TUNNEL_BOTH
+    pub tunnel: u8,
+
     #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub _pad2: [u8; 4],
+    pub _pad2: [u8; 5],
+}
+
+// ICMP type 0 это echo reply, поэтому "любой" тип / код кодируем зарезервированным 255
+pub const ICMP_ANY: u8 = 255;
+
+// К какому пакету туннеля применяется правило: внешнему, внутреннему или обоим
+pub const TUNNEL_OUTER: u8 = 0;
+pub const TUNNEL_INNER: u8 = 1;
+pub const TUNNEL_BOTH: u8 = 2;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+pub const TCP_ECE: u8 = 0x40;
+pub const TCP_CWR: u8 = 0x80;
+
+pub const TCP_FLAG_NAMES: [(&str, u8); 8] = [
+    ("FIN", TCP_FIN),
+    ("SYN", TCP_SYN),
+    ("RST", TCP_RST),
+    ("PSH", TCP_PSH),
+    ("ACK", TCP_ACK),
+    ("URG", TCP_URG),
+    ("ECE", TCP_ECE),
+    ("CWR", TCP_CWR),
+];
+
+pub const EXT_HOP_BY_HOP: u8 = 0x01;
+pub const EXT_ROUTING: u8 = 0x02;
+pub const EXT_FRAGMENT: u8 = 0x04;
+pub const EXT_DEST_OPTS: u8 = 0x08;
+pub const EXT_AH: u8 = 0x10;
+
+pub const EXT_HDR_NAMES: [(&str, u8); 5] = [
+    ("HOP", EXT_HOP_BY_HOP),
+    ("ROUTING", EXT_ROUTING),
+    ("FRAGMENT", EXT_FRAGMENT),
+    ("DEST", EXT_DEST_OPTS),
+    ("AH", EXT_AH),
+];
+
+// Счётчики срабатываний правила, per-CPU в ядре, суммируются в loader
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RuleStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub last_hit_ns: u64,
+}
+
+// Размеры map правил по умолчанию, loader может переопределить их блоком `maps` в main.yaml.
+// Сколько правил помещается в RULES
+pub const MAX_RULES: u32 = 4096;
+// Сколько разных классов пакетов и номеров правил во всех списках кандидатов
+pub const MAX_RULE_CLASSES: u32 = 4096;
+pub const MAX_CANDIDATES: u32 = 65536;
+
+// Класс пакета для предварительной классификации: по нему ядро находит
+// список правил-кандидатов и проверяет только их
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RuleClass {
+    pub proto: u8,
+    pub input: bool,
+    // 0 - пакет без портов или порт, для которого нет отдельного списка
+    pub port: u16,
+}
+
+// Класс для протоколов, которых нет ни в одном правиле
+pub const CLASS_PROTO_ANY: u8 = 255;
+
+// Отрезок RULES_CANDIDATES, номера правил в RULES по возрастанию
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RuleCandidates {
+    pub start: u32,
+    pub len: u32,
 }
 
 #[derive(PartialEq, Eq)]
@@ -46,39 +159,44 @@ pub enum Action {
     Drop = 1,
     Ok = 2,
     Pipe = 3,
+    // Пакет превысил лимит правила limit
+    Limit = 4,
+    // Ответить TCP RST или ICMP unreachable
+    Reject = 5,
+    // Правило только пишет в лог, пакет идёт дальше
+    Monitor = 6,
 }
 
 impl Rule {
     pub fn to_action(&self) -> Action {
+        if self.monitor {
+            return Action::Monitor;
+        }
+        if self.reject {
+            return Action::Reject;
+        }
         if self.drop {
             return Action::Drop;
         }
         if self.ok {
             return Action::Ok;
         }
+        if self.limit {
+            return Action::Limit;
+        }
         Action::Pipe
     }
-    pub fn is_source_v4_not_empty(&self) -> bool {
-        self.source_addr_v4 != 0 || self.source_port_start != 0 || self.source_port_end != 0
-    }
-    pub fn is_source_v6_not_empty(&self) -> bool {
-        self.source_addr_v6 != 0 || self.source_port_start != 0 || self.source_port_end != 0
-    }
-    pub fn is_destination_v4_not_empty(&self) -> bool {
-        self.destination_addr_v4 != 0
-            || self.destination_port_start != 0
-            || self.destination_port_end != 0
-    }
-    pub fn is_destination_v6_not_empty(&self) -> bool {
-        self.destination_addr_v6 != 0
-            || self.destination_port_start != 0
-            || self.destination_port_end != 0
-    }
 }
 
 #[cfg(feature = "user")]
 pub mod rules {
-    use crate::rules::Rule;
+    use crate::ipproto::{self, IP_PROTO_ANY};
+    use crate::rules::{
+        EXT_HDR_NAMES, ICMP_ANY, Rule, RuleCandidates, RuleClass, RuleStats, TCP_FLAG_NAMES,
+        TUNNEL_BOTH, TUNNEL_INNER, TUNNEL_OUTER,
+    };
+    use crate::sets::IP_SET_NONE;
+    use crate::sets::sets::IpSet;
     use libc::if_nametoindex;
     use poem_openapi::Object;
     use rand::Rng;
@@ -88,8 +206,11 @@ pub mod rules {
     use yaml_rust2::Yaml;
 
     unsafe impl aya::Pod for Rule {}
+    unsafe impl aya::Pod for RuleStats {}
+    unsafe impl aya::Pod for RuleClass {}
+    unsafe impl aya::Pod for RuleCandidates {}
 
-    #[derive(Clone, Debug, Deserialize, Serialize, Object)]
+    #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Object)]
     pub struct RuleWithName {
         pub name: String,
         pub order: u32,
@@ -100,6 +221,8 @@ pub mod rules {
         pub v6: bool,
         pub tcp: bool,
         pub udp: bool,
+        pub icmp: bool,
+        pub icmpv6: bool,
         pub on: bool,
 
         pub src_ip_high: u64,
@@ -117,21 +240,63 @@ pub mod rules {
         pub destination_port_start: u16,
         pub destination_port_end: u16,
 
+        pub vlan_id: u16,
+
         pub input: bool,
         pub output: bool,
+        pub established: bool,
+
+        pub reject: bool,
+        pub monitor: bool,
+        pub fragments: bool,
+
+        pub limit: bool,
+        pub limit_rate: u32,
+        pub limit_burst: u32,
+        pub limit_bytes: bool,
+        pub limit_per_source: bool,
+
+        pub tcp_flags: u8,
+        pub tcp_flags_mask: u8,
+
+        pub ext_hdrs: u8,
+        pub ext_hdrs_mask: u8,
+        pub ip_proto: u8,
+
+        pub sctp: bool,
+        pub dccp: bool,
+        pub udplite: bool,
+        pub tunnel: u8,
 
         pub source_mask_v4: u8,
         pub destination_mask_v4: u8,
         pub source_mask_v6: u8,
         pub destination_mask_v6: u8,
 
+        pub icmp_type: u8,
+        pub icmp_code: u8,
+
+        // Имена IP сетов, пустая строка - сет не используется
+        pub source_set: String,
+        pub destination_set: String,
+
         pub from_db: bool,
     }
 
+    #[derive(Clone, Debug, Deserialize, Serialize, Object)]
+    pub struct RuleStatsSerialized {
+        pub rule_id: u32,
+        pub packets: u64,
+        pub bytes: u64,
+        pub last_hit_ns: u64,
+        pub last_hit: u64,
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Control {
         pub action: ControlAction,
         pub rule: RuleWithName,
+        pub ip_set: IpSet,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -140,6 +305,12 @@ pub mod rules {
         GetRules = 1,
         UpdateRule = 2,
         CreateRule = 3,
+        GetRuleStats = 4,
+        GetSets = 5,
+        UpdateSet = 6,
+        DeleteSet = 7,
+        // Перечитать `tunables` из main.yaml
+        ReloadTunables = 8,
     }
 
     fn parse_network_v4(addr: &str) -> (u32, u8) {
@@ -170,7 +341,44 @@ pub mod rules {
         (addr.parse::<Ipv6Addr>().unwrap().to_bits(), 0)
     }
 
-    fn get_ifindex_by_name(name: &str) -> u32 {
+    // Список имён через запятую ("SYN,ACK"), "NONE", "ALL" или число
+    fn parse_flags(yaml: &Yaml, names: &[(&str, u8)]) -> Option<u8> {
+        if let Some(flags) = yaml.as_i64() {
+            return Some(flags as u8);
+        }
+        let flags = yaml.as_str()?;
+        let mut result = 0u8;
+        for name in flags.split(",").map(|name| name.trim().to_uppercase()) {
+            result |= match name.as_str() {
+                "" | "NONE" => 0,
+                "ALL" => 0xFF,
+                _ => match names.iter().find(|(flag, _)| *flag == name) {
+                    Some((_, bit)) => *bit,
+                    None => panic!("Unknown flag {}", name),
+                },
+            };
+        }
+        Some(result)
+    }
+
+    fn flags_to_string(flags: u8, names: &[(&str, u8)]) -> String {
+        names
+            .iter()
+            .filter(|(_, bit)| flags & bit != 0)
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+
+    pub fn tcp_flags_to_string(flags: u8) -> String {
+        flags_to_string(flags, &TCP_FLAG_NAMES)
+    }
+
+    pub fn ext_hdrs_to_string(ext_hdrs: u8) -> String {
+        flags_to_string(ext_hdrs, &EXT_HDR_NAMES)
+    }
+
+    pub fn get_ifindex_by_name(name: &str) -> u32 {
         if name.contains("*") {
             return 0u32;
         }
@@ -188,13 +396,58 @@ pub mod rules {
             let tcp = yaml["tcp"].as_bool().unwrap();
             let udp = yaml["udp"].as_bool().unwrap();
 
+            let icmp = yaml["icmp"].as_bool().unwrap_or(false);
+            let icmpv6 = yaml["icmpv6"].as_bool().unwrap_or(false);
+            let sctp = yaml["sctp"].as_bool().unwrap_or(false);
+            let dccp = yaml["dccp"].as_bool().unwrap_or(false);
+            let udplite = yaml["udplite"].as_bool().unwrap_or(false);
+            let icmp_type = yaml["icmp_type"].as_i64().map_or(ICMP_ANY, |t| t as u8);
+            let icmp_code = yaml["icmp_code"].as_i64().map_or(ICMP_ANY, |c| c as u8);
+
+            let source_set = yaml["source_set"].as_str().unwrap_or("");
+            let destination_set = yaml["destination_set"].as_str().unwrap_or("");
+
             let on = yaml["on"].as_bool().unwrap();
 
             let ok = yaml["ok"].as_bool().unwrap();
             let drop = yaml["drop"].as_bool().unwrap();
+            let reject = yaml["reject"].as_bool().unwrap_or(false);
+            let monitor = yaml["monitor"].as_bool().unwrap_or(false);
+            let fragments = yaml["fragments"].as_bool().unwrap_or(false);
 
             let input = yaml["input"].as_bool().unwrap();
             let output = yaml["output"].as_bool().unwrap();
+            let established = yaml["established"].as_bool().unwrap_or(false);
+
+            let limit = yaml["limit"].as_bool().unwrap_or(false);
+            let limit_rate = yaml["limit_rate"].as_i64().unwrap_or(0) as u32;
+            let limit_burst = yaml["limit_burst"].as_i64().unwrap_or(0) as u32;
+            let limit_bytes = yaml["limit_bytes"].as_bool().unwrap_or(false);
+            let limit_per_source = yaml["limit_per_source"].as_bool().unwrap_or(false);
+
+            // Без маски проверяем только то, что указанные флаги выставлены
+            let tcp_flags = parse_flags(&yaml["tcp_flags"], &TCP_FLAG_NAMES).unwrap_or(0);
+            let tcp_flags_mask =
+                parse_flags(&yaml["tcp_flags_mask"], &TCP_FLAG_NAMES).unwrap_or(tcp_flags);
+
+            let ext_hdrs = parse_flags(&yaml["ext_hdrs"], &EXT_HDR_NAMES).unwrap_or(0);
+            let ext_hdrs_mask =
+                parse_flags(&yaml["ext_hdrs_mask"], &EXT_HDR_NAMES).unwrap_or(ext_hdrs);
+
+            // Номер протокола или имя: gre, esp, ospf, sctp, ...
+            let ip_proto = match &yaml["ip_proto"] {
+                Yaml::Integer(proto) => *proto as u8,
+                Yaml::String(name) => ipproto::from_str_to_u8(name)
+                    .unwrap_or_else(|| panic!("Unknown ip_proto: {}", name)),
+                _ => IP_PROTO_ANY,
+            };
+
+            let tunnel = match yaml["tunnel"].as_str() {
+                Some("outer") | None => TUNNEL_OUTER,
+                Some("inner") => TUNNEL_INNER,
+                Some("both") => TUNNEL_BOTH,
+                Some(tunnel) => panic!("Unknown tunnel: {}", tunnel),
+            };
 
             let v4 = yaml["v4"].as_bool().unwrap();
             let v6 = yaml["v6"].as_bool().unwrap();
@@ -212,6 +465,8 @@ pub mod rules {
                 yaml["destination_port_start"].as_i64().unwrap() as u16;
             let destination_port_end: u16 = yaml["destination_port_end"].as_i64().unwrap() as u16;
 
+            let vlan_id = yaml["vlan_id"].as_i64().unwrap_or(0) as u16;
+
             let iface = yaml["iface"].as_str().unwrap();
             let ifindex = get_ifindex_by_name(iface);
 
@@ -241,17 +496,41 @@ pub mod rules {
 
                 tcp,
                 udp,
+                icmp,
+                icmpv6,
 
                 on,
 
                 input,
                 output,
+                established,
+
+                reject,
+                monitor,
+                fragments,
+
+                limit,
+                limit_rate,
+                limit_burst,
+                limit_bytes,
+                limit_per_source,
+
+                tcp_flags,
+                tcp_flags_mask,
+                ext_hdrs,
+                ext_hdrs_mask,
+                ip_proto,
+                sctp,
+                dccp,
+                udplite,
+                tunnel,
 
                 source_port_start,
                 source_port_end,
 
                 destination_port_start,
                 destination_port_end,
+                vlan_id,
 
                 source_addr_v4,
                 destination_addr_v4,
@@ -270,6 +549,11 @@ pub mod rules {
 
                 source_mask_v6,
                 destination_mask_v6,
+
+                icmp_type,
+                icmp_code,
+                source_set: source_set.to_string(),
+                destination_set: destination_set.to_string(),
                 from_db: false,
             }
         }
@@ -283,10 +567,16 @@ pub mod rules {
                 v6: self.v6,
                 tcp: self.tcp,
                 udp: self.udp,
+                icmp: self.icmp,
+                icmpv6: self.icmpv6,
                 on: self.on,
                 input: self.input,
                 output: self.output,
-                _pad1: [0; 5],
+                established: self.established,
+                limit: self.limit,
+                reject: self.reject,
+                monitor: self.monitor,
+                fragments: self.fragments,
 
                 source_addr_v6: ((self.src_ip_high as u128) << 64) | (self.src_ip_low as u128),
                 destination_addr_v6: ((self.dst_ip_high as u128) << 64) | (self.dst_ip_low as u128),
@@ -295,16 +585,39 @@ pub mod rules {
                 destination_addr_v4: self.destination_addr_v4,
                 rule_id: self.rule_id,
                 ifindex: self.ifindex,
+                // id сетов проставляет loader, в ядре нужны id, а не имена
+                source_set_id: IP_SET_NONE,
+                destination_set_id: IP_SET_NONE,
+
+                limit_rate: self.limit_rate,
+                limit_burst: self.limit_burst,
 
                 source_port_start: self.source_port_start,
                 source_port_end: self.source_port_end,
                 destination_port_start: self.destination_port_start,
                 destination_port_end: self.destination_port_end,
+                vlan_id: self.vlan_id,
 
                 source_mask_v4: self.source_mask_v4,
                 destination_mask_v4: self.destination_mask_v4,
                 source_mask_v6: self.source_mask_v6,
                 destination_mask_v6: self.destination_mask_v6,
+
+                icmp_type: self.icmp_type,
+                icmp_code: self.icmp_code,
+
+                limit_bytes: self.limit_bytes,
+                limit_per_source: self.limit_per_source,
+
+                tcp_flags: self.tcp_flags,
+                tcp_flags_mask: self.tcp_flags_mask,
+                ext_hdrs: self.ext_hdrs,
+                ext_hdrs_mask: self.ext_hdrs_mask,
+                ip_proto: self.ip_proto,
+                sctp: self.sctp,
+                dccp: self.dccp,
+                udplite: self.udplite,
+                tunnel: self.tunnel,
             }
         }
 
@@ -317,11 +630,16 @@ pub mod rules {
                 v6: self.v6,
                 tcp: self.tcp,
                 udp: self.udp,
+                icmp: self.icmp,
+                icmpv6: self.icmpv6,
                 on: self.on,
                 input: self.input,
                 output: self.output,
-                _reserved: false,
-                _pad1: [0; 6],
+                established: self.established,
+                limit: self.limit,
+                reject: self.reject,
+                monitor: self.monitor,
+                fragments: self.fragments,
 
                 source_addr_v6: ((self.src_ip_high as u128) << 64) | (self.src_ip_low as u128),
                 destination_addr_v6: ((self.dst_ip_high as u128) << 64) | (self.dst_ip_low as u128),
@@ -330,17 +648,40 @@ pub mod rules {
                 destination_addr_v4: self.destination_addr_v4,
                 rule_id: self.rule_id,
                 ifindex: self.ifindex,
+                // id сетов проставляет loader, в ядре нужны id, а не имена
+                source_set_id: IP_SET_NONE,
+                destination_set_id: IP_SET_NONE,
+
+                limit_rate: self.limit_rate,
+                limit_burst: self.limit_burst,
 
                 source_port_start: self.source_port_start,
                 source_port_end: self.source_port_end,
                 destination_port_start: self.destination_port_start,
                 destination_port_end: self.destination_port_end,
+                vlan_id: self.vlan_id,
 
                 source_mask_v4: self.source_mask_v4,
                 destination_mask_v4: self.destination_mask_v4,
                 source_mask_v6: self.source_mask_v6,
                 destination_mask_v6: self.destination_mask_v6,
-                _pad2: [0; 4],
+
+                icmp_type: self.icmp_type,
+                icmp_code: self.icmp_code,
+
+                limit_bytes: self.limit_bytes,
+                limit_per_source: self.limit_per_source,
+
+                tcp_flags: self.tcp_flags,
+                tcp_flags_mask: self.tcp_flags_mask,
+                ext_hdrs: self.ext_hdrs,
+                ext_hdrs_mask: self.ext_hdrs_mask,
+                ip_proto: self.ip_proto,
+                sctp: self.sctp,
+                dccp: self.dccp,
+                udplite: self.udplite,
+                tunnel: self.tunnel,
+                _pad2: [0; 5],
             }
         }
 
@@ -357,17 +698,40 @@ pub mod rules {
 
                 tcp: false,
                 udp: false,
+                icmp: false,
+                icmpv6: false,
 
                 on: false,
 
                 input: false,
                 output: false,
+                established: false,
+
+                reject: false,
+                monitor: false,
+                fragments: false,
+                limit: false,
+                limit_rate: 0,
+                limit_burst: 0,
+                limit_bytes: false,
+                limit_per_source: false,
+
+                tcp_flags: 0,
+                tcp_flags_mask: 0,
+                ext_hdrs: 0,
+                ext_hdrs_mask: 0,
+                ip_proto: 0,
+                sctp: false,
+                dccp: false,
+                udplite: false,
+                tunnel: TUNNEL_OUTER,
 
                 source_port_start: 0,
                 source_port_end: 0,
 
                 destination_port_start: 0,
                 destination_port_end: 0,
+                vlan_id: 0,
 
                 source_addr_v4: 0,
                 destination_addr_v4: 0,
@@ -386,6 +750,11 @@ pub mod rules {
 
                 source_mask_v6: 0,
                 destination_mask_v6: 0,
+
+                icmp_type: ICMP_ANY,
+                icmp_code: ICMP_ANY,
+                source_set: String::new(),
+                destination_set: String::new(),
                 from_db: false,
             }
         }
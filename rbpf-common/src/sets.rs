@@ -0,0 +1,129 @@
+// Правило без сета
+pub const IP_SET_NONE: u32 = 0;
+
+// Первые 32 бита ключа LPM trie - id сета, поэтому prefix_len = IP_SET_ID_BITS + маска адреса
+pub const IP_SET_ID_BITS: u32 = 32;
+
+// Все поля в network byte order, иначе LPM trie сравнивает префиксы не с той стороны
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IpSetV4 {
+    pub set_id: u32,
+    pub addr: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IpSetV6 {
+    pub set_id: u32,
+    pub addr: [u8; 16],
+}
+
+impl IpSetV4 {
+    #[inline(always)]
+    pub fn new(set_id: u32, addr: u32) -> Self {
+        Self {
+            set_id: set_id.to_be(),
+            addr: addr.to_be(),
+        }
+    }
+}
+
+impl IpSetV6 {
+    #[inline(always)]
+    pub fn new(set_id: u32, addr: u128) -> Self {
+        Self {
+            set_id: set_id.to_be(),
+            addr: addr.to_be_bytes(),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+pub mod sets {
+    use crate::sets::{IP_SET_NONE, IpSetV4, IpSetV6};
+    use poem_openapi::Object;
+    use serde::{Deserialize, Serialize};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    unsafe impl aya::Pod for IpSetV4 {}
+    unsafe impl aya::Pod for IpSetV6 {}
+
+    #[derive(Clone, Debug, Deserialize, Serialize, Object)]
+    pub struct IpSet {
+        pub name: String,
+        pub set_id: u32,
+        pub entries: Vec<String>,
+        pub from_db: bool,
+    }
+
+    pub enum IpNetwork {
+        V4(u32, u8),
+        V6(u128, u8),
+    }
+
+    impl IpNetwork {
+        // "10.0.0.0/8", "2001:db8::/32" или просто адрес
+        pub fn parse(entry: &str) -> Option<Self> {
+            let (addr, mask) = match entry.split_once("/") {
+                Some((addr, mask)) => (addr, Some(mask.parse::<u8>().ok()?)),
+                None => (entry, None),
+            };
+            if addr.contains(":") {
+                let addr = addr.parse::<Ipv6Addr>().ok()?.to_bits();
+                let mask = mask.unwrap_or(128);
+                if mask > 128 {
+                    return None;
+                }
+                return Some(IpNetwork::V6(addr, mask));
+            }
+            let addr = addr.parse::<Ipv4Addr>().ok()?.to_bits();
+            let mask = mask.unwrap_or(32);
+            if mask > 32 {
+                return None;
+            }
+            Some(IpNetwork::V4(addr, mask))
+        }
+
+        // Адрес с обнулёнными битами хоста, так сеть хранится в LPM trie
+        pub fn network(self) -> Self {
+            match self {
+                IpNetwork::V4(addr, mask) => IpNetwork::V4(
+                    addr & u32::MAX.checked_shl(32 - mask as u32).unwrap_or(0),
+                    mask,
+                ),
+                IpNetwork::V6(addr, mask) => IpNetwork::V6(
+                    addr & u128::MAX.checked_shl(128 - mask as u32).unwrap_or(0),
+                    mask,
+                ),
+            }
+        }
+    }
+
+    impl IpSet {
+        // Одна запись на строку, пустые строки и комментарии после # пропускаются
+        pub fn from_list(name: &str, list: &str) -> Self {
+            let entries = list
+                .lines()
+                .map(|line| line.split('#').next().unwrap().trim())
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect();
+            Self {
+                name: name.to_string(),
+                set_id: IP_SET_NONE,
+                entries,
+                from_db: false,
+            }
+        }
+
+        pub fn from_empty() -> Self {
+            Self {
+                name: String::new(),
+                set_id: IP_SET_NONE,
+                entries: Vec::new(),
+                from_db: false,
+            }
+        }
+    }
+}
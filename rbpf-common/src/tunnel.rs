@@ -0,0 +1,101 @@
+// Тип туннеля, внутренний пакет которого разобран
+pub const TUNNEL_NONE: u8 = 0;
+pub const TUNNEL_VXLAN: u8 = 1;
+pub const TUNNEL_GENEVE: u8 = 2;
+pub const TUNNEL_GRE: u8 = 3;
+// IPIP, 6in4, 4in6 и 6in6
+pub const TUNNEL_IPIP: u8 = 4;
+
+// Внутренний пакет туннеля, пишется в лог вместе с внешним
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct InnerTuple {
+    // Для IPv4 адрес лежит в младших 32 битах *_low
+    pub src_ip_high: u64,
+    pub src_ip_low: u64,
+    pub dst_ip_high: u64,
+    pub dst_ip_low: u64,
+
+    pub source_port: u16,
+    pub destination_port: u16,
+
+    pub kind: u8,
+    pub proto: u8,
+    pub v4: bool,
+    pub _pad: u8,
+}
+
+impl InnerTuple {
+    pub const fn empty() -> Self {
+        Self {
+            src_ip_high: 0,
+            src_ip_low: 0,
+            dst_ip_high: 0,
+            dst_ip_low: 0,
+            source_port: 0,
+            destination_port: 0,
+            kind: TUNNEL_NONE,
+            proto: 0,
+            v4: false,
+            _pad: 0,
+        }
+    }
+}
+
+pub fn tunnel_to_str(kind: u8) -> &'static str {
+    match kind {
+        TUNNEL_VXLAN => "VXLAN",
+        TUNNEL_GENEVE => "Geneve",
+        TUNNEL_GRE => "GRE",
+        TUNNEL_IPIP => "IPIP",
+        _ => "",
+    }
+}
+
+#[cfg(feature = "user")]
+pub mod tunnel {
+    extern crate alloc;
+    use crate::tunnel::{InnerTuple, tunnel_to_str};
+    use alloc::string::{String, ToString};
+    use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct InnerTupleSerialized {
+        pub tunnel: String,
+        pub protocol: u8,
+        pub source_addr: IpAddr,
+        pub destination_addr: IpAddr,
+        pub source_port: u16,
+        pub destination_port: u16,
+    }
+
+    impl InnerTuple {
+        pub fn source_addr(&self) -> IpAddr {
+            to_addr(self.v4, self.src_ip_high, self.src_ip_low)
+        }
+
+        pub fn destination_addr(&self) -> IpAddr {
+            to_addr(self.v4, self.dst_ip_high, self.dst_ip_low)
+        }
+
+        pub fn to_serialized(&self) -> InnerTupleSerialized {
+            InnerTupleSerialized {
+                tunnel: tunnel_to_str(self.kind).to_string(),
+                protocol: self.proto,
+                source_addr: self.source_addr(),
+                destination_addr: self.destination_addr(),
+                source_port: self.source_port,
+                destination_port: self.destination_port,
+            }
+        }
+    }
+
+    fn to_addr(v4: bool, high: u64, low: u64) -> IpAddr {
+        if v4 {
+            IpAddr::V4(Ipv4Addr::from(low as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(((high as u128) << 64) | (low as u128)))
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use aya_ebpf::helpers::bpf_get_prandom_u32;
+use aya_ebpf::macros::map;
+use aya_ebpf::maps::{Array, HashMap};
+use rbpf_common::config::{
+    FEATURE_DECAP, GlobalConfig, IfaceConfig, LINK_RAW_IP, POLICY_UNSET, Policy,
+};
+use rbpf_common::logs::{MONITOR, WARN};
+use rbpf_common::rules::Action;
+
+const MAX_IFACES: u32 = 256;
+
+// Ключ - ifindex, 0 - политика для всех интерфейсов ("*")
+#[map]
+static IFACE_CONFIG: HashMap<u32, IfaceConfig> = HashMap::with_max_entries(MAX_IFACES, 0);
+
+#[map]
+static GLOBAL_CONFIG: Array<GlobalConfig> = Array::with_max_entries(1, 0);
+
+#[inline(always)]
+fn global() -> GlobalConfig {
+    match GLOBAL_CONFIG.get(0) {
+        Some(global) => *global,
+        None => GlobalConfig::empty(),
+    }
+}
+
+#[inline(always)]
+pub fn is_enabled(feature: u32) -> bool {
+    global().is_enabled(feature)
+}
+
+// Пишется ли событие этого уровня в ring buffer
+#[inline(always)]
+pub fn is_logged(level: u8) -> bool {
+    let global = global();
+    if level == MONITOR {
+        return true;
+    }
+    if level < global.log_level {
+        return false;
+    }
+    level >= WARN
+        || global.log_sample_rate <= 1
+        || unsafe { bpf_get_prandom_u32() } % global.log_sample_rate == 0
+}
+
+// Вердикт для пакета с неразобранным L2 заголовком, по умолчанию drop
+#[inline(always)]
+pub fn malformed() -> u8 {
+    match global().malformed {
+        POLICY_UNSET => Action::Drop as u8,
+        verdict => verdict,
+    }
+}
+
+#[inline(always)]
+pub fn policy(ifindex: u32, input: bool) -> Policy {
+    let mut policy = Policy::DEFAULT;
+    if let Some(global) = unsafe { IFACE_CONFIG.get(&0) } {
+        policy.merge(global.policy(input));
+    }
+    if let Some(iface) = unsafe { IFACE_CONFIG.get(&ifindex) } {
+        policy.merge(iface.policy(input));
+    }
+    policy
+}
+
+// Разбор туннелей включается для интерфейса или для всех ("*")
+#[inline(always)]
+pub fn is_decap(ifindex: u32) -> bool {
+    if !is_enabled(FEATURE_DECAP) {
+        return false;
+    }
+    let global = match unsafe { IFACE_CONFIG.get(&0) } {
+        Some(global) => global.decap,
+        None => false,
+    };
+    global
+        || match unsafe { IFACE_CONFIG.get(&ifindex) } {
+            Some(iface) => iface.decap,
+            None => false,
+        }
+}
+
+#[inline(always)]
+pub fn is_raw_ip(ifindex: u32) -> bool {
+    match unsafe { IFACE_CONFIG.get(&ifindex) } {
+        Some(iface) => iface.link_type == LINK_RAW_IP,
+        None => false,
+    }
+}
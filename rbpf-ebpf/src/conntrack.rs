@@ -0,0 +1,289 @@
+use crate::config;
+use crate::ip::parser_result::{ParseResult, is_icmp_echo};
+use crate::ip::{ContextWrapper, UnhandledProtocolError};
+use crate::logs::now_ns;
+use aya_ebpf::macros::map;
+use aya_ebpf::maps::LruHashMap;
+use network_types::icmp::IcmpHdr;
+use network_types::ip::{IpProto, Ipv4Hdr, Ipv6Hdr};
+use rbpf_common::config::FEATURE_CONNTRACK;
+use rbpf_common::rules::{TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+
+const MAX_CONNECTIONS: u32 = 65536;
+
+const NEW: u8 = 1;
+const ESTABLISHED: u8 = 2;
+const CLOSING: u8 = 3;
+
+const SECOND: u64 = 1_000_000_000;
+const TCP_NEW_TIMEOUT: u64 = 120 * SECOND;
+const TCP_ESTABLISHED_TIMEOUT: u64 = 5 * 24 * 3600 * SECOND;
+const TCP_CLOSING_TIMEOUT: u64 = 120 * SECOND;
+const UDP_NEW_TIMEOUT: u64 = 30 * SECOND;
+const UDP_ESTABLISHED_TIMEOUT: u64 = 180 * SECOND;
+const ICMP_TIMEOUT: u64 = 30 * SECOND;
+
+const ICMP_HDR_LEN: usize = 8;
+
+// Соединение всегда хранится так, как его видит исходящий траффик: local -> remote
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConnKey {
+    pub local_addr: u128,
+    pub remote_addr: u128,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub proto: u8,
+    pub v4: bool,
+    pub _pad: [u8; 10],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConnState {
+    pub last_seen: u64,
+    pub state: u8,
+    pub _pad: [u8; 7],
+}
+
+#[map]
+static CONNTRACK: LruHashMap<ConnKey, ConnState> = LruHashMap::with_max_entries(MAX_CONNECTIONS, 0);
+
+#[inline(always)]
+fn addr(v4: bool, addr_v4: u32, addr_v6: u128) -> u128 {
+    if v4 { addr_v4 as u128 } else { addr_v6 }
+}
+
+impl ConnKey {
+    #[inline(always)]
+    fn outgoing(pac: &ParseResult) -> Self {
+        // ping различаем по идентификатору echo, он одинаков в запросе и ответе
+        let (local_port, remote_port) = if pac.is_icmp() {
+            (pac.icmp_id, 0)
+        } else {
+            (pac.source_port, pac.destination_port)
+        };
+        Self {
+            local_addr: addr(pac.v4, pac.source_addr_v4, pac.source_addr_v6),
+            remote_addr: addr(pac.v4, pac.destination_addr_v4, pac.destination_addr_v6),
+            local_port,
+            remote_port,
+            proto: pac.proto as u8,
+            v4: pac.v4,
+            _pad: [0; 10],
+        }
+    }
+
+    #[inline(always)]
+    fn incoming(pac: &ParseResult) -> Self {
+        let (local_port, remote_port) = if pac.is_icmp() {
+            (pac.icmp_id, 0)
+        } else {
+            (pac.destination_port, pac.source_port)
+        };
+        Self {
+            local_addr: addr(pac.v4, pac.destination_addr_v4, pac.destination_addr_v6),
+            remote_addr: addr(pac.v4, pac.source_addr_v4, pac.source_addr_v6),
+            local_port,
+            remote_port,
+            proto: pac.proto as u8,
+            v4: pac.v4,
+            _pad: [0; 10],
+        }
+    }
+}
+
+#[inline(always)]
+fn timeout(proto: u8, state: u8) -> u64 {
+    if proto == IpProto::Tcp as u8 {
+        return match state {
+            NEW => TCP_NEW_TIMEOUT,
+            CLOSING => TCP_CLOSING_TIMEOUT,
+            _ => TCP_ESTABLISHED_TIMEOUT,
+        };
+    }
+    if proto == IpProto::Icmp as u8 || proto == IpProto::Ipv6Icmp as u8 {
+        return ICMP_TIMEOUT;
+    }
+    match state {
+        NEW => UDP_NEW_TIMEOUT,
+        _ => UDP_ESTABLISHED_TIMEOUT,
+    }
+}
+
+#[inline(always)]
+fn is_alive(key: &ConnKey, conn: &ConnState, now: u64) -> bool {
+    now.saturating_sub(conn.last_seen) <= timeout(key.proto, conn.state)
+}
+
+#[inline(always)]
+fn is_icmp_error(pac: &ParseResult) -> bool {
+    if pac.v4 {
+        // destination unreachable, source quench, redirect, time exceeded, parameter problem
+        matches!(pac.icmp_type, 3 | 4 | 5 | 11 | 12)
+    } else {
+        // destination unreachable, packet too big, time exceeded, parameter problem
+        matches!(pac.icmp_type, 1..=4)
+    }
+}
+
+/// Исходящий пакет разрешён - заводим или обновляем соединение.
+#[inline(always)]
+pub fn track(pac: &ParseResult) {
+    if !config::is_enabled(FEATURE_CONNTRACK) {
+        return;
+    }
+    // Из ICMP соединение открывает только echo запрос, ответы на чужой ping и ошибки не отслеживаем
+    if pac.is_icmp() && !pac.is_echo_request() {
+        return;
+    }
+    let key = ConnKey::outgoing(pac);
+    let is_tcp = pac.is_tcp();
+
+    if is_tcp && pac.tcp_flags & TCP_RST != 0 {
+        let _ = CONNTRACK.remove(&key);
+        return;
+    }
+
+    let now = now_ns();
+    match CONNTRACK.get_ptr_mut(&key) {
+        Some(conn) => {
+            let conn = unsafe { &mut *conn };
+            conn.last_seen = now;
+            if is_tcp && pac.tcp_flags & TCP_FIN != 0 {
+                conn.state = CLOSING;
+            }
+        }
+        None => {
+            // Соединения, которые мы застали посередине (например после перезапуска), считаем установленными
+            let state = if is_tcp && pac.tcp_flags & (TCP_SYN | TCP_ACK) != TCP_SYN {
+                ESTABLISHED
+            } else {
+                NEW
+            };
+            let conn = ConnState {
+                last_seen: now,
+                state,
+                _pad: [0; 7],
+            };
+            let _ = CONNTRACK.insert(&key, &conn, 0);
+        }
+    }
+}
+
+/// Исходящий пакет принадлежит соединению, на которое уже был ответ.
+#[inline(always)]
+pub fn is_established_out(pac: &ParseResult) -> bool {
+    if !config::is_enabled(FEATURE_CONNTRACK) {
+        return false;
+    }
+    let key = ConnKey::outgoing(pac);
+    match unsafe { CONNTRACK.get(&key) } {
+        Some(conn) => conn.state != NEW && is_alive(&key, conn, now_ns()),
+        None => false,
+    }
+}
+
+/// Входящий пакет - ответ на разрешённое исходящее соединение. Обновляет состояние TCP.
+#[inline(always)]
+pub fn is_established_in(pac: &ParseResult) -> bool {
+    // Входящий ICMP, кроме ошибок (их проверяет related_key), может быть только ответом на наш ping
+    if pac.is_icmp() && !pac.is_echo_reply() {
+        return false;
+    }
+    let key = ConnKey::incoming(pac);
+    let conn = match CONNTRACK.get_ptr_mut(&key) {
+        Some(conn) => unsafe { &mut *conn },
+        None => return false,
+    };
+
+    let now = now_ns();
+    if !is_alive(&key, conn, now) {
+        let _ = CONNTRACK.remove(&key);
+        return false;
+    }
+
+    if pac.is_tcp() {
+        if pac.tcp_flags & TCP_RST != 0 {
+            let _ = CONNTRACK.remove(&key);
+            return true;
+        }
+        if pac.tcp_flags & TCP_FIN != 0 {
+            conn.state = CLOSING;
+        } else if conn.state == NEW && pac.tcp_flags & TCP_ACK != 0 {
+            conn.state = ESTABLISHED;
+        }
+    } else if conn.state == NEW {
+        conn.state = ESTABLISHED;
+    }
+    conn.last_seen = now;
+    true
+}
+
+impl ContextWrapper {
+    #[inline(always)]
+    pub fn conntrack_ingress(&self, pac: &ParseResult) -> bool {
+        if !config::is_enabled(FEATURE_CONNTRACK) {
+            return false;
+        }
+        if pac.is_icmp() && is_icmp_error(pac) {
+            return match self.related_key(pac) {
+                Ok(key) => match unsafe { CONNTRACK.get(&key) } {
+                    Some(conn) => is_alive(&key, conn, now_ns()),
+                    None => false,
+                },
+                Err(_) => false,
+            };
+        }
+        is_established_in(pac)
+    }
+
+    // ICMP ошибка содержит заголовок нашего исходящего пакета, по нему и ищем соединение
+    #[inline(always)]
+    fn related_key(&self, pac: &ParseResult) -> Result<ConnKey, UnhandledProtocolError> {
+        let offset = pac.l4_offset + ICMP_HDR_LEN;
+        let (proto, local_addr, remote_addr, len) = if pac.v4 {
+            let ipv4hdr: Ipv4Hdr = unsafe { *self.ptr_at_u(offset)? };
+            (
+                ipv4hdr.proto,
+                u32::from_be(ipv4hdr.src_addr) as u128,
+                u32::from_be(ipv4hdr.dst_addr) as u128,
+                ipv4hdr.ihl() as usize * 4,
+            )
+        } else {
+            let ipv6hdr: Ipv6Hdr = unsafe { *self.ptr_at_u(offset)? };
+            (
+                ipv6hdr.next_hdr,
+                u128::from_be_bytes(unsafe { ipv6hdr.src_addr.in6_u.u6_addr8 }),
+                u128::from_be_bytes(unsafe { ipv6hdr.dst_addr.in6_u.u6_addr8 }),
+                Ipv6Hdr::LEN,
+            )
+        };
+
+        let (local_port, remote_port) = match proto {
+            IpProto::Tcp | IpProto::Udp | IpProto::Sctp | IpProto::Dccp | IpProto::UdpLite => {
+                let ports: [u16; 2] = unsafe { *self.ptr_at_u(offset + len)? };
+                (u16::from_be(ports[0]), u16::from_be(ports[1]))
+            }
+            // Ошибка на наш ping, соединение записано по идентификатору echo запроса
+            IpProto::Icmp | IpProto::Ipv6Icmp => {
+                let icmphdr: IcmpHdr = unsafe { *self.ptr_at_u(offset + len)? };
+                if !is_icmp_echo(pac.v4, icmphdr.type_) {
+                    return Err(UnhandledProtocolError::empty());
+                }
+                (u16::from_be(unsafe { icmphdr.un.echo.id }), 0)
+            }
+            _ => (0, 0),
+        };
+
+        Ok(ConnKey {
+            local_addr,
+            remote_addr,
+            local_port,
+            remote_port,
+            proto: proto as u8,
+            v4: pac.v4,
+            _pad: [0; 10],
+        })
+    }
+}
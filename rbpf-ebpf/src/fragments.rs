@@ -0,0 +1,89 @@
+use crate::config;
+use crate::ip::parser_result::ParseResult;
+use aya_ebpf::macros::map;
+use aya_ebpf::maps::LruHashMap;
+use rbpf_common::config::{POLICY_TRACK, Policy};
+
+const MAX_FRAGMENTS: u32 = 16384;
+
+pub const FRAG_NONE: u8 = 0;
+// Смещение 0 и выставлен MF: в пакете есть L4 заголовок
+pub const FRAG_FIRST: u8 = 1;
+// Продолжение, L4 заголовка нет
+pub const FRAG_LATER: u8 = 2;
+
+const VERDICT_PASS: u8 = 1;
+const VERDICT_DROP: u8 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FragKey {
+    pub source_addr: u128,
+    pub destination_addr: u128,
+    pub id: u32,
+    pub proto: u8,
+    pub v4: bool,
+    pub input: bool,
+    pub _pad: [u8; 9],
+}
+
+// Вердикт первого фрагмента, по нему пропускаются или блокируются остальные
+#[map]
+static FRAGMENTS: LruHashMap<FragKey, u8> = LruHashMap::with_max_entries(MAX_FRAGMENTS, 0);
+
+pub enum FragVerdict {
+    Rules,
+    Pass,
+    Drop,
+}
+
+#[inline(always)]
+fn key(pac: &ParseResult) -> FragKey {
+    let (source_addr, destination_addr) = if pac.v4 {
+        (pac.source_addr_v4 as u128, pac.destination_addr_v4 as u128)
+    } else {
+        (pac.source_addr_v6, pac.destination_addr_v6)
+    };
+    FragKey {
+        source_addr,
+        destination_addr,
+        id: pac.frag_id,
+        proto: pac.proto as u8,
+        v4: pac.v4,
+        input: pac.input,
+        _pad: [0; 9],
+    }
+}
+
+/// Решает, что делать с фрагментом до проверки правил.
+#[inline(always)]
+pub fn check(pac: &ParseResult) -> FragVerdict {
+    if pac.fragment == FRAG_NONE {
+        return FragVerdict::Rules;
+    }
+    let verdict = config::policy(pac.ifindex, pac.input).fragments;
+    if Policy::is_drop(verdict) {
+        return FragVerdict::Drop;
+    }
+    if verdict != POLICY_TRACK || pac.fragment == FRAG_FIRST {
+        return FragVerdict::Rules;
+    }
+    // Первый фрагмент не видели (потерялся или пришёл позже) - пропускать нечего
+    match unsafe { FRAGMENTS.get(&key(pac)) } {
+        Some(verdict) if *verdict == VERDICT_PASS => FragVerdict::Pass,
+        _ => FragVerdict::Drop,
+    }
+}
+
+/// Запоминает вердикт первого фрагмента для политики track.
+#[inline(always)]
+pub fn remember(pac: &ParseResult, pass: bool) {
+    if pac.fragment != FRAG_FIRST {
+        return;
+    }
+    if config::policy(pac.ifindex, pac.input).fragments != POLICY_TRACK {
+        return;
+    }
+    let verdict = if pass { VERDICT_PASS } else { VERDICT_DROP };
+    let _ = FRAGMENTS.insert(&key(pac), &verdict, 0);
+}
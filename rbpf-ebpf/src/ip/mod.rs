@@ -1,16 +1,110 @@
 pub mod ipproto;
 pub mod parser_result;
+pub mod tunnel;
 pub mod v4;
 pub mod v6;
+pub mod verdict;
 
-use aya_ebpf::bindings::{TC_ACT_PIPE, TC_ACT_SHOT, xdp_action};
+use crate::config;
+use crate::fragments::{FRAG_FIRST, FRAG_LATER, FRAG_NONE};
+use aya_ebpf::bindings::{__sk_buff, xdp_md};
+use aya_ebpf::helpers::bpf_xdp_get_buff_len;
 use aya_ebpf::programs::{TcContext, XdpContext};
+use core::ffi::c_void;
 use core::net::Ipv6Addr;
-use network_types::eth::{EthHdr, EtherType};
+use network_types::eth::EthHdr;
+use network_types::icmp::IcmpHdr;
 use network_types::ip::{IpProto, Ipv4Hdr, Ipv6Hdr};
 use network_types::tcp::TcpHdr;
 use network_types::udp::UdpHdr;
 use parser_result::ParseResult;
+use rbpf_common::config::Policy;
+use rbpf_common::rules::{EXT_AH, EXT_DEST_OPTS, EXT_FRAGMENT, EXT_HOP_BY_HOP, EXT_ROUTING};
+use rbpf_common::tunnel::InnerTuple;
+use verdict::Verdict;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86DD;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88A8;
+
+pub const VLAN_HDR_LEN: usize = 4;
+// 802.1Q или QinQ (802.1ad + 802.1Q)
+pub const MAX_VLAN_TAGS: usize = 2;
+const VLAN_VID_MASK: u16 = 0x0FFF;
+
+// Идёт после TPID (0x8100 / 0x88a8), который лежит на месте EtherType
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VlanHdr {
+    tci: u16,
+    ether_type: u16,
+}
+
+// SCTP, DCCP и UDP-Lite начинаются с портов так же, как TCP / UDP
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PortsHdr {
+    source: u16,
+    dest: u16,
+}
+
+// Сколько заголовков расширения IPv6 проходим до L4, дальше - политика ext_too_deep
+const MAX_EXT_HDRS: usize = 6;
+
+// Общее начало Hop-by-Hop, Routing, Fragment, Destination Options и AH
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ipv6ExtHdr {
+    next_hdr: IpProto,
+    hdr_len: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Ipv6FragHdr {
+    next_hdr: IpProto,
+    reserved: u8,
+    // Смещение в 8-байтных блоках << 3, младший бит - M (есть ещё фрагменты)
+    frag_off: u16,
+    id: u32,
+}
+
+// IPv4 frag_off: флаг MF и смещение в 8-байтных блоках
+const IP_MF: u16 = 0x2000;
+const IP_OFFSET: u16 = 0x1FFF;
+
+// Что известно после IP заголовка и заголовков расширения
+struct L3Info {
+    proto: IpProto,
+    l4_offset: usize,
+    ext_hdrs: u8,
+    fragment: u8,
+    frag_id: u32,
+}
+
+#[inline(always)]
+fn fragment_state(offset: u16, more: bool) -> u8 {
+    if offset != 0 {
+        FRAG_LATER
+    } else if more {
+        FRAG_FIRST
+    } else {
+        FRAG_NONE
+    }
+}
+
+#[inline(always)]
+fn ext_hdr_bit(proto: IpProto) -> u8 {
+    match proto {
+        IpProto::HopOpt => EXT_HOP_BY_HOP,
+        IpProto::Ipv6Route => EXT_ROUTING,
+        IpProto::Ipv6Frag => EXT_FRAGMENT,
+        IpProto::Ipv6Opts => EXT_DEST_OPTS,
+        IpProto::Ah => EXT_AH,
+        _ => 0,
+    }
+}
 
 pub struct UnhandledProtocolError {
     pub proto: IpProto,
@@ -22,8 +116,11 @@ pub struct UnhandledProtocolError {
     pub src_v6: u128,
 
     pub ifindex: u32,
+    pub vlan_id: u16,
     pub input: bool,
     pub v4: bool,
+    // Не дошли до L4 за MAX_EXT_HDRS заголовков расширения
+    pub ext_too_deep: bool,
 }
 
 impl UnhandledProtocolError {
@@ -35,39 +132,135 @@ impl UnhandledProtocolError {
             dst_v6: 0u128,
             src_v6: 0u128,
             ifindex: 0u32,
+            vlan_id: 0u16,
             input: false,
             v4: false,
+            ext_too_deep: false,
         }
     }
 
     pub fn proto_as_u8(&self) -> u8 {
         return ipproto::as_u8(&self.proto);
     }
+
+    #[inline(always)]
+    pub fn verdict(&self, policy: &Policy) -> u8 {
+        if self.ext_too_deep {
+            policy.ext_too_deep
+        } else {
+            policy.unhandled
+        }
+    }
+}
+
+#[inline(always)]
+fn tcp_flags(tcphdr: &TcpHdr) -> u8 {
+    (tcphdr.fin()
+        | tcphdr.syn() << 1
+        | tcphdr.rst() << 2
+        | tcphdr.psh() << 3
+        | tcphdr.ack() << 4
+        | tcphdr.urg() << 5
+        | tcphdr.ece() << 6
+        | tcphdr.cwr() << 7) as u8
 }
 
 pub struct ContextWrapper {
+    // xdp_md или __sk_buff, нужен хелперам, меняющим размер пакета
+    pub ctx: *mut c_void,
     pub data: usize,
     pub data_end: usize,
     pub ifindex: u32,
+    pub len: u32,
+    // Начало IP заголовка: 0 у L3 интерфейсов, иначе сдвигается на каждый VLAN тег
+    pub l3_offset: usize,
+    // Внешний тег, 0 - пакет без VLAN
+    pub vlan_id: u16,
+    // Контекст XDP программы, иначе TC (__sk_buff)
+    pub xdp: bool,
 }
 
 impl ContextWrapper {
     #[inline(always)]
     pub fn from_xdp(ctx: &XdpContext) -> Self {
-        unsafe { Self::from_usize(ctx.data(), ctx.data_end(), (*ctx.ctx).ingress_ifindex) }
+        unsafe { Self::from_xdp_md(ctx.ctx) }
+    }
+
+    /// Для multi-buffer XDP: data..data_end - только линейная часть, заголовки разбираются в ней,
+    /// а длина для статистики и лимитов берётся по всему пакету.
+    #[inline(always)]
+    pub fn from_xdp_frags(ctx: &XdpContext) -> Self {
+        let mut wctx = Self::from_xdp(ctx);
+        wctx.len = unsafe { bpf_xdp_get_buff_len(ctx.ctx) } as u32;
+        wctx
+    }
+
+    /// Пакет целиком лежит в линейной части
+    #[inline(always)]
+    pub fn is_linear(&self) -> bool {
+        self.len as usize <= self.data_end - self.data
     }
 
     #[inline(always)]
     pub fn from_tc(ctx: &TcContext) -> Self {
-        unsafe { Self::from_usize(ctx.data(), ctx.data_end(), (*ctx.skb.skb).ifindex) }
+        unsafe { Self::from_sk_buff(ctx.skb.skb) }
+    }
+
+    /// # Safety
+    /// `ctx` должен указывать на контекст текущей XDP программы.
+    #[inline(always)]
+    pub unsafe fn from_xdp_md(ctx: *mut xdp_md) -> Self {
+        let data = unsafe { (*ctx).data as usize };
+        let data_end = unsafe { (*ctx).data_end as usize };
+        let ifindex = unsafe { (*ctx).ingress_ifindex };
+        let mut wctx = Self::from_usize(
+            ctx as *mut c_void,
+            data,
+            data_end,
+            ifindex,
+            (data_end - data) as u32,
+        );
+        wctx.xdp = true;
+        wctx
+    }
+
+    /// # Safety
+    /// `skb` должен указывать на контекст текущей TC программы.
+    #[inline(always)]
+    pub unsafe fn from_sk_buff(skb: *mut __sk_buff) -> Self {
+        let mut wctx = unsafe {
+            Self::from_usize(
+                skb as *mut c_void,
+                (*skb).data as usize,
+                (*skb).data_end as usize,
+                (*skb).ifindex,
+                (*skb).len,
+            )
+        };
+        // Внешний тег ядро часто держит в метаданных skb, а не в самом пакете
+        if unsafe { (*skb).vlan_present } != 0 {
+            wctx.vlan_id = unsafe { (*skb).vlan_tci } as u16 & VLAN_VID_MASK;
+        }
+        wctx
     }
 
     #[inline(always)]
-    pub fn from_usize(data: usize, data_end: usize, ifindex: u32) -> Self {
+    pub fn from_usize(
+        ctx: *mut c_void,
+        data: usize,
+        data_end: usize,
+        ifindex: u32,
+        len: u32,
+    ) -> Self {
         Self {
+            ctx,
             data,
             data_end,
             ifindex,
+            len,
+            l3_offset: EthHdr::LEN,
+            vlan_id: 0,
+            xdp: false,
         }
     }
 
@@ -80,61 +273,222 @@ impl ContextWrapper {
         Ok((self.data + offset) as *const T)
     }
 
+    #[inline(always)]
+    pub fn ptr_at_mut<T>(&self, offset: usize) -> Result<*mut T, UnhandledProtocolError> {
+        Ok(self.ptr_at_u::<T>(offset)? as *mut T)
+    }
+
+    /// Пропускает Ethernet заголовок и VLAN теги, возвращает EtherType вложенного протокола (host byte order).
+    #[inline(always)]
+    pub fn parse_l2(&mut self) -> Result<u16, UnhandledProtocolError> {
+        // У L3 интерфейсов заголовка нет, протокол определяем по версии IP
+        if config::is_raw_ip(self.ifindex) {
+            self.l3_offset = 0;
+            let version: u8 = unsafe { *self.ptr_at_u(0)? };
+            return Ok(match version >> 4 {
+                4 => ETH_P_IP,
+                6 => ETH_P_IPV6,
+                _ => 0,
+            });
+        }
+
+        let ether_type: u16 = unsafe { *self.ptr_at_u(EthHdr::LEN - 2)? };
+        let mut ether_type = u16::from_be(ether_type);
+        for _ in 0..MAX_VLAN_TAGS {
+            if ether_type != ETH_P_8021Q && ether_type != ETH_P_8021AD {
+                break;
+            }
+            let vlan: VlanHdr = unsafe { *self.ptr_at_u(self.l3_offset)? };
+            if self.vlan_id == 0 {
+                self.vlan_id = u16::from_be(vlan.tci) & VLAN_VID_MASK;
+            }
+            ether_type = u16::from_be(vlan.ether_type);
+            self.l3_offset += VLAN_HDR_LEN;
+        }
+        Ok(ether_type)
+    }
+
+    /// Проходит заголовки расширения IPv6 до L4 протокола, запоминая встреченные заголовки и фрагментацию.
+    #[inline(always)]
+    fn ipv6_ext_hdrs(&self, next_hdr: IpProto) -> Result<L3Info, UnhandledProtocolError> {
+        let mut info = L3Info {
+            proto: next_hdr,
+            l4_offset: self.l3_offset + Ipv6Hdr::LEN,
+            ext_hdrs: 0,
+            fragment: FRAG_NONE,
+            frag_id: 0,
+        };
+        for _ in 0..MAX_EXT_HDRS {
+            let bit = ext_hdr_bit(info.proto);
+            if bit == 0 {
+                return Ok(info);
+            }
+            info.ext_hdrs |= bit;
+            if bit == EXT_FRAGMENT {
+                let frag: Ipv6FragHdr = unsafe { *self.ptr_at_u(info.l4_offset)? };
+                let frag_off = u16::from_be(frag.frag_off);
+                info.fragment = fragment_state(frag_off >> 3, frag_off & 1 != 0);
+                info.frag_id = u32::from_be(frag.id);
+                info.proto = frag.next_hdr;
+                info.l4_offset += size_of::<Ipv6FragHdr>();
+                // Дальше только данные, заголовков в продолжении фрагмента нет
+                if info.fragment == FRAG_LATER {
+                    return Ok(info);
+                }
+                continue;
+            }
+            let hdr: Ipv6ExtHdr = unsafe { *self.ptr_at_u(info.l4_offset)? };
+            info.proto = hdr.next_hdr;
+            // Длина у AH в 4-байтных словах, у остальных в 8-байтных
+            info.l4_offset += if bit == EXT_AH {
+                (hdr.hdr_len as usize + 2) * 4
+            } else {
+                (hdr.hdr_len as usize + 1) * 8
+            };
+        }
+        if ext_hdr_bit(info.proto) != 0 {
+            let mut err = UnhandledProtocolError::empty();
+            err.proto = info.proto;
+            err.ext_too_deep = true;
+            return Err(err);
+        }
+        Ok(info)
+    }
+
     pub fn to_parse_result(
         &self,
         v4: bool,
         input: bool,
     ) -> Result<ParseResult, UnhandledProtocolError> {
-        let (proto, destination_addr_v4, source_addr_v4, destination_addr_v6, source_addr_v6) =
-            if v4 {
-                let ipv4hdr: Ipv4Hdr = unsafe { *self.ptr_at_u(EthHdr::LEN)? };
-                (
-                    ipv4hdr.proto,
-                    u32::from_be(ipv4hdr.dst_addr),
-                    u32::from_be(ipv4hdr.src_addr),
-                    0u128,
-                    0u128,
-                )
-            } else {
-                let ipv6hdr: Ipv6Hdr = unsafe { *self.ptr_at_u(EthHdr::LEN)? };
-
-                (
-                    ipv6hdr.next_hdr,
-                    0u32,
-                    0u32,
-                    Ipv6Addr::from(unsafe { ipv6hdr.dst_addr.in6_u.u6_addr8 }).to_bits(),
-                    Ipv6Addr::from(unsafe { ipv6hdr.src_addr.in6_u.u6_addr8 }).to_bits(),
-                )
-            };
+        let (destination_addr_v4, source_addr_v4, destination_addr_v6, source_addr_v6, info) = if v4
+        {
+            let ipv4hdr: Ipv4Hdr = unsafe { *self.ptr_at_u(self.l3_offset)? };
+            // IHL в 4-байтных словах, больше 5 - есть опции
+            let len = ipv4hdr.ihl() as usize * 4;
+            if len < Ipv4Hdr::LEN {
+                let mut err = UnhandledProtocolError::empty();
+                err.proto = ipv4hdr.proto;
+                err.dst_v4 = u32::from_be(ipv4hdr.dst_addr);
+                err.src_v4 = u32::from_be(ipv4hdr.src_addr);
+                err.ifindex = self.ifindex;
+                err.vlan_id = self.vlan_id;
+                err.input = input;
+                err.v4 = true;
+                return Err(err);
+            }
+            let frag_off = u16::from_be(ipv4hdr.frag_off);
+            (
+                u32::from_be(ipv4hdr.dst_addr),
+                u32::from_be(ipv4hdr.src_addr),
+                0u128,
+                0u128,
+                Ok(L3Info {
+                    proto: ipv4hdr.proto,
+                    l4_offset: self.l3_offset + len,
+                    ext_hdrs: 0,
+                    fragment: fragment_state(frag_off & IP_OFFSET, frag_off & IP_MF != 0),
+                    frag_id: u16::from_be(ipv4hdr.id) as u32,
+                }),
+            )
+        } else {
+            let ipv6hdr: Ipv6Hdr = unsafe { *self.ptr_at_u(self.l3_offset)? };
 
-        let len = if v4 { Ipv4Hdr::LEN } else { Ipv6Hdr::LEN };
+            (
+                0u32,
+                0u32,
+                Ipv6Addr::from(unsafe { ipv6hdr.dst_addr.in6_u.u6_addr8 }).to_bits(),
+                Ipv6Addr::from(unsafe { ipv6hdr.src_addr.in6_u.u6_addr8 }).to_bits(),
+                self.ipv6_ext_hdrs(ipv6hdr.next_hdr),
+            )
+        };
 
-        let (source_port, destination_port) = match proto {
-            IpProto::Tcp => {
-                let tcphdr: TcpHdr = unsafe { *self.ptr_at_u(EthHdr::LEN + len)? };
-                (u16::from_be(tcphdr.source), u16::from_be(tcphdr.dest))
-            }
-            IpProto::Udp => {
-                let udphdr: UdpHdr = unsafe { *self.ptr_at_u(EthHdr::LEN + len)? };
-                (u16::from_be(udphdr.source), u16::from_be(udphdr.dest))
-            }
-            _ => {
-                return Err(UnhandledProtocolError {
-                    proto,
-                    dst_v4: destination_addr_v4,
-                    src_v4: source_addr_v4,
-                    dst_v6: destination_addr_v6,
-                    src_v6: source_addr_v6,
-                    ifindex: self.ifindex,
-                    input,
-                    v4,
-                });
+        let L3Info {
+            proto,
+            l4_offset,
+            ext_hdrs,
+            fragment,
+            frag_id,
+        } = match info {
+            Ok(info) => info,
+            Err(mut err) => {
+                err.dst_v6 = destination_addr_v6;
+                err.src_v6 = source_addr_v6;
+                err.ifindex = self.ifindex;
+                err.vlan_id = self.vlan_id;
+                err.input = input;
+                return Err(err);
             }
         };
 
+        // В продолжении фрагмента L4 заголовка нет, протокол берём из IP заголовка как есть
+        let (source_port, destination_port, icmp_type, icmp_code, icmp_id, tcp_flags) =
+            if fragment == FRAG_LATER {
+                (0, 0, 0, 0, 0, 0)
+            } else {
+                match proto {
+                    IpProto::Tcp => {
+                        let tcphdr: TcpHdr = unsafe { *self.ptr_at_u(l4_offset)? };
+                        (
+                            u16::from_be(tcphdr.source),
+                            u16::from_be(tcphdr.dest),
+                            0,
+                            0,
+                            0,
+                            tcp_flags(&tcphdr),
+                        )
+                    }
+                    IpProto::Udp => {
+                        let udphdr: UdpHdr = unsafe { *self.ptr_at_u(l4_offset)? };
+                        (
+                            u16::from_be(udphdr.source),
+                            u16::from_be(udphdr.dest),
+                            0,
+                            0,
+                            0,
+                            0,
+                        )
+                    }
+                    IpProto::Sctp | IpProto::Dccp | IpProto::UdpLite => {
+                        let portshdr: PortsHdr = unsafe { *self.ptr_at_u(l4_offset)? };
+                        (
+                            u16::from_be(portshdr.source),
+                            u16::from_be(portshdr.dest),
+                            0,
+                            0,
+                            0,
+                            0,
+                        )
+                    }
+                    // Заголовки ICMP и ICMPv6 совпадают по первым байтам: type, code, checksum.
+                    IpProto::Icmp | IpProto::Ipv6Icmp => {
+                        let icmphdr: IcmpHdr = unsafe { *self.ptr_at_u(l4_offset)? };
+                        // У echo запроса и ответа дальше идёт идентификатор, по нему отслеживается ping
+                        let icmp_id = if parser_result::is_icmp_echo(v4, icmphdr.type_) {
+                            u16::from_be(unsafe { icmphdr.un.echo.id })
+                        } else {
+                            0
+                        };
+                        (0, 0, icmphdr.type_, icmphdr.code, icmp_id, 0)
+                    }
+                    // L4 не разбираем, такой пакет проверяется только правилами с ip_proto
+                    _ => (0, 0, 0, 0, 0, 0),
+                }
+            };
+
         Ok(ParseResult {
             source_port,
             destination_port,
+            icmp_type,
+            icmp_code,
+            icmp_id,
+            tcp_flags,
+            established: false,
+            l3_offset: self.l3_offset,
+            l4_offset,
+            ext_hdrs,
+            fragment,
+            frag_id,
+            vlan_id: self.vlan_id,
             source_addr_v4,
             destination_addr_v4,
             proto,
@@ -144,35 +498,48 @@ impl ContextWrapper {
             output: !input,
             v4,
             ifindex: self.ifindex,
+            len: self.len,
+            inner: InnerTuple::empty(),
         })
     }
 
-    pub fn handle_as_tc(&self) -> i32 {
-        let hdr = {
-            match self.ptr_at_u::<EthHdr>(0) {
-                Ok(ptr) => ptr,
-                Err(_) => return TC_ACT_SHOT,
-            }
+    pub fn handle_as_tc(&mut self) -> i32 {
+        self.handle_egress().to_tc()
+    }
+
+    pub fn handle_as_tc_ingress(&mut self) -> i32 {
+        self.handle_ingress().to_tc()
+    }
+
+    pub fn handle_as_xdp(&mut self) -> u32 {
+        self.handle_ingress().to_xdp()
+    }
+
+    #[inline(always)]
+    fn handle_ingress(&mut self) -> Verdict {
+        let ether_type = match self.parse_l2() {
+            Ok(ether_type) => ether_type,
+            Err(_) => return Verdict::from_policy(config::malformed()),
         };
 
-        match unsafe { (*hdr).ether_type } {
-            EtherType::Ipv4 => self.handle_egress_v4(),
-            EtherType::Ipv6 => self.handle_egress_v6(),
-            _ => TC_ACT_PIPE,
+        match ether_type {
+            ETH_P_IP => self.handle_ingress_v4(),
+            ETH_P_IPV6 => self.handle_ingress_v6(),
+            _ => Verdict::from_policy(config::policy(self.ifindex, true).non_ip),
         }
     }
-    pub fn handle_as_xdp(&self) -> u32 {
-        let hdr = {
-            match self.ptr_at_u::<EthHdr>(0) {
-                Ok(ptr) => ptr,
-                Err(_) => return xdp_action::XDP_DROP,
-            }
+
+    #[inline(always)]
+    fn handle_egress(&mut self) -> Verdict {
+        let ether_type = match self.parse_l2() {
+            Ok(ether_type) => ether_type,
+            Err(_) => return Verdict::from_policy(config::malformed()),
         };
 
-        match unsafe { (*hdr).ether_type } {
-            EtherType::Ipv4 => self.handle_ingress_v4(),
-            EtherType::Ipv6 => self.handle_ingress_v6(),
-            _ => xdp_action::XDP_PASS,
+        match ether_type {
+            ETH_P_IP => self.handle_egress_v4(),
+            ETH_P_IPV6 => self.handle_egress_v6(),
+            _ => Verdict::from_policy(config::policy(self.ifindex, false).non_ip),
         }
     }
 }
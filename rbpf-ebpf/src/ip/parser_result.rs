@@ -1,10 +1,48 @@
+use crate::fragments::{FRAG_LATER, FRAG_NONE};
+use crate::ip::UnhandledProtocolError;
+use crate::sets;
 use network_types::ip::IpProto;
-use rbpf_common::rules::{Action, Rule};
+use rbpf_common::ipproto::IP_PROTO_ANY;
+use rbpf_common::rules::{Action, ICMP_ANY, Rule};
+use rbpf_common::sets::IP_SET_NONE;
+use rbpf_common::tunnel::InnerTuple;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+#[inline(always)]
+pub fn is_icmp_echo(v4: bool, icmp_type: u8) -> bool {
+    if v4 {
+        icmp_type == ICMP_ECHO_REQUEST || icmp_type == ICMP_ECHO_REPLY
+    } else {
+        icmp_type == ICMPV6_ECHO_REQUEST || icmp_type == ICMPV6_ECHO_REPLY
+    }
+}
 
 pub struct ParseResult {
     pub source_port: u16,
     pub destination_port: u16,
 
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    // Идентификатор ICMP echo запроса / ответа, у остальных ICMP 0
+    pub icmp_id: u16,
+    pub tcp_flags: u8,
+
+    // Пакет принадлежит отслеживаемому соединению (или связан с ним через ICMP ошибку)
+    pub established: bool,
+    pub l3_offset: usize,
+    pub l4_offset: usize,
+    // Встреченные заголовки расширения IPv6, EXT_*
+    pub ext_hdrs: u8,
+    // FRAG_* и идентификатор фрагментированного пакета
+    pub fragment: u8,
+    pub frag_id: u32,
+    // Внешний VLAN тег, 0 - без тега
+    pub vlan_id: u16,
+
     pub destination_addr_v4: u32,
     pub source_addr_v4: u32,
 
@@ -18,6 +56,10 @@ pub struct ParseResult {
 
     pub v4: bool,
     pub ifindex: u32,
+    pub len: u32,
+
+    // Внутренний пакет туннеля, заполняется только при decap
+    pub inner: InnerTuple,
 }
 
 impl ParseResult {
@@ -27,6 +69,109 @@ impl ParseResult {
     pub fn is_udp(&self) -> bool {
         self.proto == IpProto::Udp
     }
+    pub fn is_icmp(&self) -> bool {
+        self.proto == IpProto::Icmp || self.proto == IpProto::Ipv6Icmp
+    }
+    pub fn is_echo_request(&self) -> bool {
+        let echo_request = if self.v4 {
+            ICMP_ECHO_REQUEST
+        } else {
+            ICMPV6_ECHO_REQUEST
+        };
+        self.is_icmp() && self.icmp_type == echo_request
+    }
+    pub fn is_echo_reply(&self) -> bool {
+        let echo_reply = if self.v4 {
+            ICMP_ECHO_REPLY
+        } else {
+            ICMPV6_ECHO_REPLY
+        };
+        self.is_icmp() && self.icmp_type == echo_reply
+    }
+    pub fn is_sctp(&self) -> bool {
+        self.proto == IpProto::Sctp
+    }
+    pub fn is_dccp(&self) -> bool {
+        self.proto == IpProto::Dccp
+    }
+    pub fn is_udplite(&self) -> bool {
+        self.proto == IpProto::UdpLite
+    }
+
+    // Протоколы, у которых L4 заголовок начинается с портов
+    pub fn is_port_proto(&self) -> bool {
+        self.is_tcp() || self.is_udp() || self.is_sctp() || self.is_dccp() || self.is_udplite()
+    }
+
+    // Остальные протоколы проверяются только правилами с ip_proto
+    pub fn is_known_proto(&self) -> bool {
+        self.is_port_proto() || self.is_icmp()
+    }
+
+    pub fn has_ports(&self) -> bool {
+        self.is_port_proto() && self.fragment != FRAG_LATER
+    }
+
+    pub fn to_unhandled(&self) -> UnhandledProtocolError {
+        UnhandledProtocolError {
+            proto: self.proto,
+            dst_v4: self.destination_addr_v4,
+            src_v4: self.source_addr_v4,
+            dst_v6: self.destination_addr_v6,
+            src_v6: self.source_addr_v6,
+            ifindex: self.ifindex,
+            vlan_id: self.vlan_id,
+            input: self.input,
+            v4: self.v4,
+            ext_too_deep: false,
+        }
+    }
+
+    // Правило с портами не срабатывает на пакеты, у которых портов нет
+    #[inline(always)]
+    pub fn is_ports(&self, rule: &Rule) -> bool {
+        self.has_ports()
+            || (rule.source_port_start == 0
+                && rule.source_port_end == 0
+                && rule.destination_port_start == 0
+                && rule.destination_port_end == 0)
+    }
+
+    pub fn is_icmp_type(&self, rule: &Rule) -> bool {
+        if !self.is_icmp() {
+            return true;
+        }
+        let is_type = rule.icmp_type == ICMP_ANY || self.icmp_type == rule.icmp_type;
+        let is_code = rule.icmp_code == ICMP_ANY || self.icmp_code == rule.icmp_code;
+        is_type && is_code
+    }
+
+    // У продолжения фрагмента нет L4 заголовка, правила с ICMP типом или TCP флагами на него не срабатывают
+    #[inline(always)]
+    pub fn is_fragment(&self, rule: &Rule) -> bool {
+        if rule.fragments && self.fragment == FRAG_NONE {
+            return false;
+        }
+        if self.fragment != FRAG_LATER {
+            return true;
+        }
+        rule.icmp_type == ICMP_ANY && rule.icmp_code == ICMP_ANY && rule.tcp_flags_mask == 0
+    }
+
+    #[inline(always)]
+    pub fn is_ext_hdrs(&self, rule: &Rule) -> bool {
+        (self.ext_hdrs & rule.ext_hdrs_mask) == rule.ext_hdrs
+    }
+
+    #[inline(always)]
+    pub fn is_vlan(&self, rule: &Rule) -> bool {
+        rule.vlan_id == 0 || rule.vlan_id == self.vlan_id
+    }
+
+    #[inline(always)]
+    pub fn is_tcp_flags(&self, rule: &Rule) -> bool {
+        !self.is_tcp() || (self.tcp_flags & rule.tcp_flags_mask) == rule.tcp_flags
+    }
 
     pub fn is_source_port(&self, rule: &Rule) -> bool {
         let in_source_port_range =
@@ -47,6 +192,9 @@ impl ParseResult {
     }
 
     pub fn is_source_v4_addr(&self, rule: &Rule) -> bool {
+        if rule.source_set_id != IP_SET_NONE {
+            return sets::contains_v4(rule.source_set_id, self.source_addr_v4);
+        }
         (rule.source_addr_v4 == 0)
             || ((self.source_addr_v4 == rule.source_addr_v4)
                 || (rule.source_mask_v4 != 0
@@ -58,6 +206,9 @@ impl ParseResult {
     }
 
     pub fn is_source_v6_addr(&self, rule: &Rule) -> bool {
+        if rule.source_set_id != IP_SET_NONE {
+            return sets::contains_v6(rule.source_set_id, self.source_addr_v6);
+        }
         (rule.source_addr_v6 == 0)
             || ((self.source_addr_v6 == rule.source_addr_v6)
                 || (rule.source_mask_v6 != 0
@@ -69,6 +220,9 @@ impl ParseResult {
     }
 
     pub fn is_destination_v4_addr(&self, rule: &Rule) -> bool {
+        if rule.destination_set_id != IP_SET_NONE {
+            return sets::contains_v4(rule.destination_set_id, self.destination_addr_v4);
+        }
         (rule.destination_addr_v4 == 0)
             || ((self.destination_addr_v4 == rule.destination_addr_v4)
                 || (rule.destination_mask_v4 != 0
@@ -80,9 +234,12 @@ impl ParseResult {
     }
 
     pub fn is_destination_v6_addr(&self, rule: &Rule) -> bool {
+        if rule.destination_set_id != IP_SET_NONE {
+            return sets::contains_v6(rule.destination_set_id, self.destination_addr_v6);
+        }
         (rule.destination_addr_v6 == 0)
             || ((self.destination_addr_v6 == rule.destination_addr_v6)
-                || (rule.destination_mask_v4 != 0
+                || (rule.destination_mask_v6 != 0
                     && is_ip_in_subnet_v6(
                         self.destination_addr_v6,
                         rule.destination_addr_v6,
@@ -95,38 +252,24 @@ impl ParseResult {
             return Action::Pipe;
         }
 
-        if self.v4 && rule.v4 {
-            if self.input
-                && self.is_source_v4_addr(rule)
-                && self.is_source_port(rule)
-                && rule.is_source_v4_not_empty()
-            {
-                return rule.to_action();
-            }
-            if self.output
-                && self.is_destination_v4_addr(rule)
-                && self.is_destination_port(rule)
-                && rule.is_destination_v4_not_empty()
-            {
-                return rule.to_action();
-            }
-        }
-
-        if !self.v4 && rule.v6 {
-            if self.input
-                && self.is_source_v6_addr(rule)
-                && self.is_source_port(rule)
-                && rule.is_source_v6_not_empty()
-            {
-                return rule.to_action();
-            }
-            if self.output
-                && self.is_destination_v6_addr(rule)
-                && self.is_destination_port(rule)
-                && rule.is_destination_v6_not_empty()
-            {
-                return rule.to_action();
-            }
+        // Адреса и порты источника / назначения сверяются в обоих направлениях, пустые поля - любое значение
+        let is_addr = if self.v4 {
+            rule.v4 && self.is_source_v4_addr(rule) && self.is_destination_v4_addr(rule)
+        } else {
+            rule.v6 && self.is_source_v6_addr(rule) && self.is_destination_v6_addr(rule)
+        };
+
+        if is_addr
+            && self.is_ports(rule)
+            && self.is_source_port(rule)
+            && self.is_destination_port(rule)
+            && self.is_icmp_type(rule)
+            && self.is_tcp_flags(rule)
+            && self.is_vlan(rule)
+            && self.is_ext_hdrs(rule)
+            && self.is_fragment(rule)
+        {
+            return rule.to_action();
         }
         Action::Pipe
     }
@@ -136,6 +279,9 @@ impl ParseResult {
         if !rule.on {
             return true;
         }
+        if rule.established && !self.established {
+            return true;
+        }
         if self.v4 && !rule.v4 {
             return true;
         }
@@ -148,12 +294,34 @@ impl ParseResult {
         if self.output && !rule.output {
             return true;
         }
+        // Явный номер протокола заменяет флаги tcp / udp / icmp / icmpv6
+        if rule.ip_proto != IP_PROTO_ANY {
+            return self.proto as u8 != rule.ip_proto;
+        }
+        if !self.is_known_proto() {
+            return true;
+        }
         if (self.proto == IpProto::Tcp) && !rule.tcp {
             return true;
         }
         if (self.proto == IpProto::Udp) && !rule.udp {
             return true;
         }
+        if (self.proto == IpProto::Icmp) && !rule.icmp {
+            return true;
+        }
+        if (self.proto == IpProto::Ipv6Icmp) && !rule.icmpv6 {
+            return true;
+        }
+        if (self.proto == IpProto::Sctp) && !rule.sctp {
+            return true;
+        }
+        if (self.proto == IpProto::Dccp) && !rule.dccp {
+            return true;
+        }
+        if (self.proto == IpProto::UdpLite) && !rule.udplite {
+            return true;
+        }
         false
     }
 }
@@ -0,0 +1,150 @@
+use crate::config;
+use crate::fragments::FRAG_LATER;
+use crate::ip::parser_result::ParseResult;
+use crate::ip::{ContextWrapper, ETH_P_IP, ETH_P_IPV6, UnhandledProtocolError};
+use network_types::eth::EthHdr;
+use network_types::ip::IpProto;
+use network_types::udp::UdpHdr;
+use rbpf_common::tunnel::{
+    InnerTuple, TUNNEL_GENEVE, TUNNEL_GRE, TUNNEL_IPIP, TUNNEL_NONE, TUNNEL_VXLAN,
+};
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+const VXLAN_HDR_LEN: usize = 8;
+
+// Transparent Ethernet Bridging: внутри Ethernet кадр
+const ETH_P_TEB: u16 = 0x6558;
+
+const GRE_CSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQ: u16 = 0x1000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GreHdr {
+    flags: u16,
+    proto: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GeneveHdr {
+    // Версия (2 бита) и длина опций в 4-байтных словах (6 бит)
+    ver_opt_len: u8,
+    flags: u8,
+    proto: u16,
+    vni: [u8; 3],
+    reserved: u8,
+}
+
+impl ContextWrapper {
+    /// Разбирает внутренний пакет туннеля, если для интерфейса включён decap.
+    /// Внутренний кортеж сохраняется в pac.inner для логов.
+    #[inline(always)]
+    pub fn to_inner(&self, pac: &mut ParseResult) -> Option<ParseResult> {
+        if pac.fragment == FRAG_LATER || !config::is_decap(self.ifindex) {
+            return None;
+        }
+        let (kind, l3_offset, ether_type) = match self.tunnel_l3(pac) {
+            Ok((TUNNEL_NONE, _, _)) | Err(_) => return None,
+            Ok(tunnel) => tunnel,
+        };
+
+        // Туннель в туннеле не разбираем, внутренний пакет читаем тем же парсером
+        let inner_ctx = ContextWrapper {
+            ctx: self.ctx,
+            data: self.data,
+            data_end: self.data_end,
+            ifindex: self.ifindex,
+            len: self.len,
+            l3_offset,
+            vlan_id: self.vlan_id,
+            xdp: self.xdp,
+        };
+        let mut inner = inner_ctx
+            .to_parse_result(ether_type == ETH_P_IP, pac.input)
+            .ok()?;
+        inner.established = pac.established;
+
+        pac.inner = InnerTuple {
+            src_ip_high: (inner.source_addr_v6 >> 64) as u64,
+            src_ip_low: if inner.v4 {
+                inner.source_addr_v4 as u64
+            } else {
+                inner.source_addr_v6 as u64
+            },
+            dst_ip_high: (inner.destination_addr_v6 >> 64) as u64,
+            dst_ip_low: if inner.v4 {
+                inner.destination_addr_v4 as u64
+            } else {
+                inner.destination_addr_v6 as u64
+            },
+            source_port: inner.source_port,
+            destination_port: inner.destination_port,
+            kind,
+            proto: inner.proto as u8,
+            v4: inner.v4,
+            _pad: 0,
+        };
+        Some(inner)
+    }
+
+    // Тип туннеля, смещение внутреннего IP заголовка и его EtherType
+    #[inline(always)]
+    fn tunnel_l3(&self, pac: &ParseResult) -> Result<(u8, usize, u16), UnhandledProtocolError> {
+        match pac.proto {
+            IpProto::Udp if pac.destination_port == VXLAN_PORT => {
+                let offset = pac.l4_offset + UdpHdr::LEN + VXLAN_HDR_LEN;
+                self.inner_eth(TUNNEL_VXLAN, offset)
+            }
+            IpProto::Udp if pac.destination_port == GENEVE_PORT => {
+                let offset = pac.l4_offset + UdpHdr::LEN;
+                let geneve: GeneveHdr = unsafe { *self.ptr_at_u(offset)? };
+                let offset =
+                    offset + size_of::<GeneveHdr>() + (geneve.ver_opt_len & 0x3F) as usize * 4;
+                match u16::from_be(geneve.proto) {
+                    ETH_P_TEB => self.inner_eth(TUNNEL_GENEVE, offset),
+                    ether_type => Ok((TUNNEL_GENEVE, offset, ether_type)),
+                }
+            }
+            IpProto::Gre => {
+                let gre: GreHdr = unsafe { *self.ptr_at_u(pac.l4_offset)? };
+                let flags = u16::from_be(gre.flags);
+                // За базовым заголовком идут необязательные поля по 4 байта
+                let mut offset = pac.l4_offset + size_of::<GreHdr>();
+                if flags & GRE_CSUM != 0 {
+                    offset += 4;
+                }
+                if flags & GRE_KEY != 0 {
+                    offset += 4;
+                }
+                if flags & GRE_SEQ != 0 {
+                    offset += 4;
+                }
+                match u16::from_be(gre.proto) {
+                    ETH_P_TEB => self.inner_eth(TUNNEL_GRE, offset),
+                    ether_type => Ok((TUNNEL_GRE, offset, ether_type)),
+                }
+            }
+            IpProto::Ipv4 => Ok((TUNNEL_IPIP, pac.l4_offset, ETH_P_IP)),
+            IpProto::Ipv6 => Ok((TUNNEL_IPIP, pac.l4_offset, ETH_P_IPV6)),
+            _ => Ok((TUNNEL_NONE, 0, 0)),
+        }
+        .map(|(kind, offset, ether_type)| match ether_type {
+            ETH_P_IP | ETH_P_IPV6 => (kind, offset, ether_type),
+            _ => (TUNNEL_NONE, 0, 0),
+        })
+    }
+
+    // Внутренний Ethernet кадр, VLAN теги внутри туннеля не разбираем
+    #[inline(always)]
+    fn inner_eth(
+        &self,
+        kind: u8,
+        offset: usize,
+    ) -> Result<(u8, usize, u16), UnhandledProtocolError> {
+        let ether_type: u16 = unsafe { *self.ptr_at_u(offset + EthHdr::LEN - 2)? };
+        Ok((kind, offset + EthHdr::LEN, u16::from_be(ether_type)))
+    }
+}
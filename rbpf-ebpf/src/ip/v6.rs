@@ -1,67 +1,166 @@
+use crate::fragments::FragVerdict;
 use crate::ip::ContextWrapper;
-use crate::{logs, rules};
-use aya_ebpf::bindings::{TC_ACT_PIPE, TC_ACT_SHOT, xdp_action};
+use crate::ip::verdict::Verdict;
+use crate::{config, conntrack, fragments, logs, rules};
+use rbpf_common::config::Policy;
 use rbpf_common::logs::{DEBUG, INFO, WARN};
 use rbpf_common::rules::Action;
 
 impl ContextWrapper {
     #[inline(always)]
-    pub fn handle_ingress_v6(&self) -> u32 {
-        let ret = match self.to_parse_result(false, true) {
+    pub fn handle_ingress_v6(&self) -> Verdict {
+        let mut ret = match self.to_parse_result(false, true) {
             Ok(ret) => ret,
-            Err(proto) => {
-                return {
-                    logs::send_err_unhandled_protocol("UNHANDLED IN v6", proto);
-                    xdp_action::XDP_DROP
+            Err(err) => {
+                let message = if err.ext_too_deep {
+                    "EXT TOO DEEP IN v6"
+                } else {
+                    "UNHANDLED IN v6"
                 };
+                let verdict = err.verdict(&config::policy(self.ifindex, true));
+                logs::send_err_unhandled_protocol(message, err);
+                return Verdict::from_policy(verdict);
             }
         };
 
-        let (action, rule_id) = rules::check_rule(&ret);
+        match fragments::check(&ret) {
+            FragVerdict::Rules => {}
+            FragVerdict::Pass => {
+                logs::send_from_rule("FRAG IN v6", 0, &ret, DEBUG);
+                return Verdict::Pass;
+            }
+            FragVerdict::Drop => {
+                logs::send_from_rule("FRAG DROP IN v6", 0, &ret, WARN);
+                return Verdict::Drop;
+            }
+        }
+
+        ret.established = self.conntrack_ingress(&ret);
+        let inner = self.to_inner(&mut ret);
+        let (action, rule_id, on_inner) = rules::check_rule(&ret, inner.as_ref());
 
-        match action {
+        let verdict = match action {
             Action::Ok => {
                 logs::send_from_rule("OK IN v6", rule_id, &ret, INFO);
-                xdp_action::XDP_PASS
+                Verdict::Pass
             }
             Action::Drop => {
                 logs::send_from_rule("BAN IN v6", rule_id, &ret, WARN);
-                xdp_action::XDP_DROP
+                Verdict::Drop
             }
-            Action::Pipe => {
-                logs::send_from_rule("PIPE IN v6", 0, &ret, DEBUG);
-                xdp_action::XDP_PASS
+            Action::Limit => {
+                logs::send_from_rule("LIMIT IN v6", rule_id, &ret, WARN);
+                Verdict::Drop
             }
-        }
+            Action::Reject => {
+                logs::send_from_rule("REJECT IN v6", rule_id, &ret, WARN);
+                // Ответ собирается из внешних заголовков, на внутренний поток туннеля не отвечаем
+                if !on_inner && self.reject(&ret) {
+                    Verdict::Reply
+                } else {
+                    Verdict::Drop
+                }
+            }
+            // check_rule не возвращает Monitor, такие правила пропускают пакет дальше
+            Action::Pipe | Action::Monitor => {
+                if ret.established {
+                    logs::send_from_rule("CT IN v6", 0, &ret, DEBUG);
+                    Verdict::Pass
+                } else if !ret.is_known_proto() {
+                    // Протокол без подходящего правила с ip_proto
+                    let verdict = config::policy(self.ifindex, true).unhandled;
+                    logs::send_err_unhandled_protocol("UNHANDLED IN v6", ret.to_unhandled());
+                    Verdict::from_policy(verdict)
+                } else if Policy::is_drop(config::policy(self.ifindex, true).unmatched) {
+                    logs::send_from_rule("DEFAULT DROP IN v6", 0, &ret, WARN);
+                    Verdict::Drop
+                } else {
+                    logs::send_from_rule("PIPE IN v6", 0, &ret, DEBUG);
+                    Verdict::Pass
+                }
+            }
+        };
+        fragments::remember(&ret, verdict == Verdict::Pass);
+        verdict
     }
 
     #[inline(always)]
-    pub fn handle_egress_v6(&self) -> i32 {
-        let ret = match self.to_parse_result(false, false) {
+    pub fn handle_egress_v6(&self) -> Verdict {
+        let mut ret = match self.to_parse_result(false, false) {
             Ok(ret) => ret,
-            Err(proto) => {
-                return {
-                    logs::send_err_unhandled_protocol("UNHANDLED OUT v6", proto);
-                    TC_ACT_SHOT
+            Err(err) => {
+                let message = if err.ext_too_deep {
+                    "EXT TOO DEEP OUT v6"
+                } else {
+                    "UNHANDLED OUT v6"
                 };
+                let verdict = err.verdict(&config::policy(self.ifindex, false));
+                logs::send_err_unhandled_protocol(message, err);
+                return Verdict::from_policy(verdict);
             }
         };
 
-        let (action, rule_id) = rules::check_rule(&ret);
+        match fragments::check(&ret) {
+            FragVerdict::Rules => {}
+            FragVerdict::Pass => {
+                logs::send_from_rule("FRAG OUT v6", 0, &ret, DEBUG);
+                return Verdict::Pass;
+            }
+            FragVerdict::Drop => {
+                logs::send_from_rule("FRAG DROP OUT v6", 0, &ret, WARN);
+                return Verdict::Drop;
+            }
+        }
+
+        ret.established = conntrack::is_established_out(&ret);
+        let inner = self.to_inner(&mut ret);
+        let (action, rule_id, on_inner) = rules::check_rule(&ret, inner.as_ref());
 
-        match action {
+        let verdict = match action {
             Action::Ok => {
                 logs::send_from_rule("OK v6", rule_id, &ret, INFO);
-                TC_ACT_PIPE
+                conntrack::track(&ret);
+                Verdict::Pass
             }
             Action::Drop => {
                 logs::send_from_rule("BAN OUT v6", rule_id, &ret, WARN);
-                TC_ACT_SHOT
+                Verdict::Drop
             }
-            Action::Pipe => {
-                logs::send_from_rule("PIPE OUT v6", 0, &ret, DEBUG);
-                TC_ACT_PIPE
+            Action::Limit => {
+                logs::send_from_rule("LIMIT OUT v6", rule_id, &ret, WARN);
+                Verdict::Drop
             }
-        }
+            Action::Reject => {
+                logs::send_from_rule("REJECT OUT v6", rule_id, &ret, WARN);
+                // Ответ собирается из внешних заголовков, на внутренний поток туннеля не отвечаем
+                if !on_inner && self.reject(&ret) {
+                    Verdict::Reply
+                } else {
+                    Verdict::Drop
+                }
+            }
+            // check_rule не возвращает Monitor, такие правила пропускают пакет дальше
+            Action::Pipe | Action::Monitor => {
+                if ret.established {
+                    conntrack::track(&ret);
+                    logs::send_from_rule("CT OUT v6", 0, &ret, DEBUG);
+                    Verdict::Pass
+                } else if !ret.is_known_proto() {
+                    // Протокол без подходящего правила с ip_proto
+                    let verdict = config::policy(self.ifindex, false).unhandled;
+                    logs::send_err_unhandled_protocol("UNHANDLED OUT v6", ret.to_unhandled());
+                    Verdict::from_policy(verdict)
+                } else if Policy::is_drop(config::policy(self.ifindex, false).unmatched) {
+                    logs::send_from_rule("DEFAULT DROP OUT v6", 0, &ret, WARN);
+                    Verdict::Drop
+                } else {
+                    conntrack::track(&ret);
+                    logs::send_from_rule("PIPE OUT v6", 0, &ret, DEBUG);
+                    Verdict::Pass
+                }
+            }
+        };
+        fragments::remember(&ret, verdict == Verdict::Pass);
+        verdict
     }
 }
@@ -0,0 +1,40 @@
+use aya_ebpf::bindings::{TC_ACT_PIPE, TC_ACT_REDIRECT, TC_ACT_SHOT, xdp_action};
+use rbpf_common::config::Policy;
+
+// Решение обработчика, в код возврата XDP или TC переводится в точке входа программы
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    // Пакет переписан в ответ (reject) и уходит обратно отправителю
+    Reply,
+}
+
+impl Verdict {
+    #[inline(always)]
+    pub fn from_policy(verdict: u8) -> Self {
+        if Policy::is_drop(verdict) {
+            Verdict::Drop
+        } else {
+            Verdict::Pass
+        }
+    }
+
+    #[inline(always)]
+    pub fn to_xdp(self) -> u32 {
+        match self {
+            Verdict::Pass => xdp_action::XDP_PASS,
+            Verdict::Drop => xdp_action::XDP_DROP,
+            Verdict::Reply => xdp_action::XDP_TX,
+        }
+    }
+
+    #[inline(always)]
+    pub fn to_tc(self) -> i32 {
+        match self {
+            Verdict::Pass => TC_ACT_PIPE,
+            Verdict::Drop => TC_ACT_SHOT,
+            Verdict::Reply => TC_ACT_REDIRECT,
+        }
+    }
+}
@@ -1,4 +1,10 @@
 #![no_std]
+pub mod config;
+pub mod conntrack;
+pub mod fragments;
 pub mod ip;
+pub mod limits;
 pub mod logs;
+pub mod reject;
 pub mod rules;
+pub mod sets;
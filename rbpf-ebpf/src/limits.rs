@@ -0,0 +1,55 @@
+use crate::ip::parser_result::ParseResult;
+use crate::logs::now_ns;
+use aya_ebpf::macros::map;
+use aya_ebpf::maps::LruHashMap;
+use rbpf_common::limits;
+use rbpf_common::limits::Bucket;
+use rbpf_common::rules::Rule;
+
+const MAX_BUCKETS: u32 = 65536;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LimitKey {
+    pub addr: u128,
+    pub rule_id: u32,
+    pub v4: bool,
+    pub _pad: [u8; 11],
+}
+
+#[map]
+static LIMITS: LruHashMap<LimitKey, Bucket> = LruHashMap::with_max_entries(MAX_BUCKETS, 0);
+
+/// Token bucket правила, true - пакет превысил лимит и должен быть отброшен.
+#[inline(always)]
+pub fn is_over_limit(rule: &Rule, pac: &ParseResult) -> bool {
+    let addr = if !rule.limit_per_source {
+        0
+    } else if pac.v4 {
+        pac.source_addr_v4 as u128
+    } else {
+        pac.source_addr_v6
+    };
+    let key = LimitKey {
+        addr,
+        rule_id: rule.rule_id,
+        v4: pac.v4,
+        _pad: [0; 11],
+    };
+
+    let rate = rule.limit_rate as u64;
+    let capacity = limits::capacity(rule);
+    let cost = limits::cost(rule, pac.len);
+
+    let now = now_ns();
+    match LIMITS.get_ptr_mut(&key) {
+        Some(bucket) => unsafe { &mut *bucket }.take(rate, capacity, cost, now),
+        None => match Bucket::first(capacity, cost, now) {
+            Some(bucket) => {
+                let _ = LIMITS.insert(&key, &bucket, 0);
+                false
+            }
+            None => true,
+        },
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config;
 use crate::ip::{UnhandledProtocolError, parser_result::ParseResult};
 use aya_ebpf::helpers::bpf_ktime_get_ns;
 use aya_ebpf::macros::map;
@@ -5,6 +6,7 @@ use aya_ebpf::maps::RingBuf;
 use core::ptr::addr_of_mut;
 use network_types::ip::IpProto;
 use rbpf_common::logs::{ERROR, LogMessage};
+use rbpf_common::tunnel::InnerTuple;
 
 #[map]
 static mut LOGS_RING_BUF: RingBuf = RingBuf::with_byte_size(512 * 1024, 0);
@@ -30,17 +32,31 @@ pub fn send_from_rule(message: &str, rule_id: u32, pac: &ParseResult, level: u8)
         output: pac.output,
         udp: pac.proto == IpProto::Udp,
         tcp: pac.proto == IpProto::Tcp,
+        icmp: pac.is_icmp(),
+        sctp: pac.is_sctp(),
+        dccp: pac.is_dccp(),
+        udplite: pac.is_udplite(),
         destination_addr_v4: pac.destination_addr_v4,
         source_addr_v4: pac.source_addr_v4,
         source_port: pac.source_port,
         destination_port: pac.destination_port,
+        vlan_id: pac.vlan_id,
+        icmp_type: pac.icmp_type,
+        icmp_code: pac.icmp_code,
+        tcp_flags: pac.tcp_flags,
         src_ip_high,
         src_ip_low,
         dst_ip_high,
         dst_ip_low,
         ifindex: pac.ifindex,
-        unhandled_protocol: 255,
+        // Для протоколов без разбора L4 пишем номер, иначе его в логе не видно
+        unhandled_protocol: if pac.is_known_proto() {
+            255
+        } else {
+            pac.proto as u8
+        },
         timestamp: now_ns(),
+        inner: pac.inner,
     };
     send_log(msg);
 }
@@ -55,10 +71,18 @@ pub fn send_from(message: &str, level: u8) {
         output: false,
         udp: false,
         tcp: false,
+        icmp: false,
+        sctp: false,
+        dccp: false,
+        udplite: false,
         destination_addr_v4: 0,
         source_addr_v4: 0,
         source_port: 0,
         destination_port: 0,
+        vlan_id: 0,
+        icmp_type: 0,
+        icmp_code: 0,
+        tcp_flags: 0,
         src_ip_high: 0,
         src_ip_low: 0,
         dst_ip_high: 0,
@@ -66,6 +90,7 @@ pub fn send_from(message: &str, level: u8) {
         ifindex: 0,
         unhandled_protocol: 255,
         timestamp: now_ns(),
+        inner: InnerTuple::empty(),
     };
     send_log(msg);
 }
@@ -85,10 +110,18 @@ pub fn send_err_unhandled_protocol(message: &str, err: UnhandledProtocolError) {
         output: !err.input,
         udp: false,
         tcp: false,
+        icmp: false,
+        sctp: false,
+        dccp: false,
+        udplite: false,
         destination_addr_v4: err.dst_v4,
         source_addr_v4: err.src_v4,
         source_port: 0,
         destination_port: 0,
+        vlan_id: err.vlan_id,
+        icmp_type: 0,
+        icmp_code: 0,
+        tcp_flags: 0,
         src_ip_high,
         src_ip_low,
         dst_ip_high,
@@ -96,6 +129,7 @@ pub fn send_err_unhandled_protocol(message: &str, err: UnhandledProtocolError) {
         ifindex: err.ifindex,
         unhandled_protocol: err.proto_as_u8(),
         timestamp: now_ns(),
+        inner: InnerTuple::empty(),
     };
 
     send_log(msg);
@@ -108,6 +142,9 @@ fn str_to_u8(msg: &str) -> [u8; 128] {
 }
 
 pub fn send_log(msg: LogMessage) {
+    if !config::is_logged(msg.level) {
+        return;
+    }
     unsafe {
         let ring_buf = addr_of_mut!(LOGS_RING_BUF);
         if let Some(mut buf) = (*ring_buf).reserve::<LogMessage>(0) {
@@ -9,13 +9,27 @@ use rbpf_ebpf::ip::ContextWrapper;
 
 #[classifier]
 pub fn tc_egress(ctx: TcContext) -> i32 {
-    let wctx = ContextWrapper::from_tc(&ctx);
+    let mut wctx = ContextWrapper::from_tc(&ctx);
     wctx.handle_as_tc()
 }
 
+// Вместо XDP для интерфейсов, где он неудобен: lo, veth в контейнерах
+#[classifier]
+pub fn tc_ingress(ctx: TcContext) -> i32 {
+    let mut wctx = ContextWrapper::from_tc(&ctx);
+    wctx.handle_as_tc_ingress()
+}
+
 #[xdp]
 pub fn xdp_ingress(ctx: XdpContext) -> u32 {
-    let wctx = ContextWrapper::from_xdp(&ctx);
+    let mut wctx = ContextWrapper::from_xdp(&ctx);
+    wctx.handle_as_xdp()
+}
+
+// То же для интерфейсов с multi-buffer XDP (jumbo frames), загружается на ядрах с xdp.frags
+#[xdp(frags)]
+pub fn xdp_ingress_frags(ctx: XdpContext) -> u32 {
+    let mut wctx = ContextWrapper::from_xdp_frags(&ctx);
     wctx.handle_as_xdp()
 }
 
@@ -0,0 +1,387 @@
+use crate::fragments::FRAG_LATER;
+use crate::ip::parser_result::ParseResult;
+use crate::ip::{ContextWrapper, MAX_VLAN_TAGS, UnhandledProtocolError, VLAN_HDR_LEN};
+use aya_ebpf::bindings::bpf_adj_room_mode::BPF_ADJ_ROOM_MAC;
+use aya_ebpf::bindings::{__sk_buff, BPF_F_INGRESS, xdp_md};
+use aya_ebpf::helpers::{
+    bpf_redirect, bpf_skb_adjust_room, bpf_skb_change_tail, bpf_skb_pull_data, bpf_xdp_adjust_head,
+    bpf_xdp_adjust_tail,
+};
+use core::mem::size_of;
+use core::slice::from_raw_parts;
+use network_types::eth::EthHdr;
+use network_types::icmp::{IcmpHdr, IcmpHdrUn};
+use network_types::ip::{IpProto, Ipv4Hdr, Ipv6Hdr, in6_addr, in6_u};
+use network_types::tcp::TcpHdr;
+use rbpf_common::rules::TCP_RST;
+
+const TTL: u8 = 64;
+
+const ICMP_DEST_UNREACH: u8 = 3;
+const ICMP_PORT_UNREACH: u8 = 3;
+const ICMPV6_DEST_UNREACH: u8 = 1;
+const ICMPV6_PORT_UNREACH: u8 = 4;
+
+// В ICMP ошибку кладём IP заголовок исходного пакета и первые 8 байт L4
+const QUOTE_V4: usize = Ipv4Hdr::LEN + 8;
+const QUOTE_V6: usize = Ipv6Hdr::LEN + 8;
+
+// Псевдозаголовок для контрольной суммы TCP / ICMPv6, все поля в network byte order
+#[repr(C)]
+struct PseudoHdrV4 {
+    src_addr: u32,
+    dst_addr: u32,
+    zero: u8,
+    proto: u8,
+    len: u16,
+}
+
+#[repr(C)]
+struct PseudoHdrV6 {
+    src_addr: [u8; 16],
+    dst_addr: [u8; 16],
+    len: u32,
+    zero: [u8; 3],
+    next_hdr: u8,
+}
+
+// Что нужно знать об исходном пакете, чтобы собрать ответ после изменения его размера
+struct Origin {
+    eth: EthHdr,
+    // VLAN теги как есть, после увеличения пакета спереди их надо записать заново
+    vlan: [[u8; VLAN_HDR_LEN]; MAX_VLAN_TAGS],
+    seq: u32,
+    ack_seq: u32,
+    payload_len: u32,
+    ack: bool,
+}
+
+#[inline(always)]
+fn csum_add<T>(sum: u32, data: &T) -> u32 {
+    let words = unsafe { from_raw_parts(data as *const T as *const u16, size_of::<T>() / 2) };
+    let mut sum = sum;
+    for word in words {
+        sum += *word as u32;
+    }
+    sum
+}
+
+#[inline(always)]
+fn csum_fold(sum: u32) -> u16 {
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    !(sum as u16)
+}
+
+#[inline(always)]
+fn in6(addr: u128) -> in6_addr {
+    in6_addr {
+        in6_u: in6_u {
+            u6_addr8: addr.to_be_bytes(),
+        },
+    }
+}
+
+#[inline(always)]
+fn reply_eth(origin: &Origin) -> EthHdr {
+    EthHdr {
+        dst_addr: origin.eth.src_addr,
+        src_addr: origin.eth.dst_addr,
+        ether_type: origin.eth.ether_type,
+    }
+}
+
+#[inline(always)]
+fn reply_ipv4(pac: &ParseResult, proto: IpProto, payload_len: usize) -> Ipv4Hdr {
+    let mut ipv4hdr = Ipv4Hdr {
+        _bitfield_align_1: [],
+        _bitfield_1: Ipv4Hdr::new_bitfield_1(5, 4),
+        tos: 0,
+        tot_len: ((Ipv4Hdr::LEN + payload_len) as u16).to_be(),
+        id: 0,
+        frag_off: 0,
+        ttl: TTL,
+        proto,
+        check: 0,
+        src_addr: pac.destination_addr_v4.to_be(),
+        dst_addr: pac.source_addr_v4.to_be(),
+    };
+    ipv4hdr.check = csum_fold(csum_add(0, &ipv4hdr));
+    ipv4hdr
+}
+
+#[inline(always)]
+fn reply_ipv6(pac: &ParseResult, proto: IpProto, payload_len: usize) -> Ipv6Hdr {
+    Ipv6Hdr {
+        _bitfield_align_1: [],
+        _bitfield_1: Ipv6Hdr::new_bitfield_1(0, 6),
+        flow_label: [0; 3],
+        payload_len: (payload_len as u16).to_be(),
+        next_hdr: proto,
+        hop_limit: TTL,
+        src_addr: in6(pac.destination_addr_v6),
+        dst_addr: in6(pac.source_addr_v6),
+    }
+}
+
+#[inline(always)]
+fn pseudo_csum(pac: &ParseResult, proto: IpProto, len: usize) -> u32 {
+    if pac.v4 {
+        let pseudo = PseudoHdrV4 {
+            src_addr: pac.destination_addr_v4.to_be(),
+            dst_addr: pac.source_addr_v4.to_be(),
+            zero: 0,
+            proto: proto as u8,
+            len: (len as u16).to_be(),
+        };
+        csum_add(0, &pseudo)
+    } else {
+        let pseudo = PseudoHdrV6 {
+            src_addr: pac.destination_addr_v6.to_be_bytes(),
+            dst_addr: pac.source_addr_v6.to_be_bytes(),
+            len: (len as u32).to_be(),
+            zero: [0; 3],
+            next_hdr: proto as u8,
+        };
+        csum_add(0, &pseudo)
+    }
+}
+
+impl ContextWrapper {
+    #[inline(always)]
+    pub fn reject(&self, pac: &ParseResult) -> bool {
+        // На RST и ICMP (в том числе ICMP ошибки) не отвечаем, иначе две стороны с reject
+        // могут бесконечно слать ответы друг другу
+        if pac.is_icmp() || (pac.is_tcp() && pac.tcp_flags & TCP_RST != 0) {
+            return false;
+        }
+        if self.xdp {
+            self.reject_xdp(pac)
+        } else {
+            self.reject_tc(pac)
+        }
+    }
+
+    /// Превращает пакет в TCP RST или ICMP port unreachable и отправляет обратно через XDP_TX.
+    #[inline(always)]
+    pub fn reject_xdp(&self, pac: &ParseResult) -> bool {
+        let ctx = self.ctx as *mut xdp_md;
+        // Ответ собирается на месте пакета, хвост во фрагментах XDP буфера не переписываем
+        if !self.is_linear() {
+            return false;
+        }
+        let origin = match self.origin(pac) {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        };
+
+        let (head, total) = reply_size(pac);
+        if head != 0 && unsafe { bpf_xdp_adjust_head(ctx, -(head as i32)) } != 0 {
+            return false;
+        }
+        let cur_len = unsafe { ((*ctx).data_end - (*ctx).data) as i32 };
+        if unsafe { bpf_xdp_adjust_tail(ctx, total as i32 - cur_len) } != 0 {
+            return false;
+        }
+
+        let wctx = unsafe { Self::from_xdp_md(ctx) };
+        wctx.write_reply(pac, &origin).is_ok()
+    }
+
+    /// То же в TC: ответ на исходящий пакет заворачивается на ingress того же интерфейса,
+    /// на входящий (tc_ingress) - отправляется с него же.
+    #[inline(always)]
+    pub fn reject_tc(&self, pac: &ParseResult) -> bool {
+        let skb = self.ctx as *mut __sk_buff;
+        // adjust_room вставит место сразу после MAC заголовка, перед VLAN тегами в данных пакета
+        if pac.l3_offset != EthHdr::LEN && !pac.is_tcp() {
+            return false;
+        }
+        let origin = match self.origin(pac) {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        };
+
+        let (head, total) = reply_size(pac);
+        unsafe {
+            if head != 0 && bpf_skb_adjust_room(skb, head as i32, BPF_ADJ_ROOM_MAC, 0) != 0 {
+                return false;
+            }
+            if bpf_skb_change_tail(skb, total as u32, 0) != 0 {
+                return false;
+            }
+            if bpf_skb_pull_data(skb, total as u32) != 0 {
+                return false;
+            }
+        }
+
+        let wctx = unsafe { Self::from_sk_buff(skb) };
+        if wctx.write_reply(pac, &origin).is_err() {
+            return false;
+        }
+        let flags = if pac.input { 0 } else { BPF_F_INGRESS as u64 };
+        unsafe { bpf_redirect(self.ifindex, flags) };
+        true
+    }
+
+    #[inline(always)]
+    fn origin(&self, pac: &ParseResult) -> Result<Origin, UnhandledProtocolError> {
+        // У L3 интерфейсов нет MAC адресов, а у продолжения фрагмента - L4 заголовка, такой ответ не собрать
+        if pac.l3_offset == 0 || pac.fragment == FRAG_LATER {
+            return Err(UnhandledProtocolError::empty());
+        }
+        let eth: EthHdr = unsafe { *self.ptr_at_u(0)? };
+        let mut vlan = [[0u8; VLAN_HDR_LEN]; MAX_VLAN_TAGS];
+        for (i, tag) in vlan.iter_mut().enumerate() {
+            let offset = EthHdr::LEN + i * VLAN_HDR_LEN;
+            if offset < pac.l3_offset {
+                *tag = unsafe { *self.ptr_at_u(offset)? };
+            }
+        }
+        let quote = if pac.v4 { QUOTE_V4 } else { QUOTE_V6 };
+
+        if !pac.is_tcp() {
+            // Короткий пакет нечем цитировать
+            if self.data + pac.l3_offset + quote > self.data_end {
+                return Err(UnhandledProtocolError::empty());
+            }
+            return Ok(Origin {
+                eth,
+                vlan,
+                seq: 0,
+                ack_seq: 0,
+                payload_len: 0,
+                ack: false,
+            });
+        }
+
+        let tcphdr: TcpHdr = unsafe { *self.ptr_at_u(pac.l4_offset)? };
+        // Опции IPv4 и заголовки расширения IPv6 тоже не payload
+        let ip_len = if pac.v4 {
+            let ipv4hdr: Ipv4Hdr = unsafe { *self.ptr_at_u(pac.l3_offset)? };
+            u16::from_be(ipv4hdr.tot_len) as u32
+        } else {
+            let ipv6hdr: Ipv6Hdr = unsafe { *self.ptr_at_u(pac.l3_offset)? };
+            u16::from_be(ipv6hdr.payload_len) as u32 + Ipv6Hdr::LEN as u32
+        };
+        let ip_payload_len = ip_len.saturating_sub((pac.l4_offset - pac.l3_offset) as u32);
+        // SYN и FIN занимают по одному номеру последовательности
+        let payload_len = ip_payload_len.saturating_sub(tcphdr.doff() as u32 * 4)
+            + tcphdr.syn() as u32
+            + tcphdr.fin() as u32;
+
+        Ok(Origin {
+            eth,
+            vlan,
+            seq: u32::from_be(tcphdr.seq),
+            ack_seq: u32::from_be(tcphdr.ack_seq),
+            payload_len,
+            ack: tcphdr.ack() != 0,
+        })
+    }
+
+    #[inline(always)]
+    fn write_reply(
+        &self,
+        pac: &ParseResult,
+        origin: &Origin,
+    ) -> Result<(), UnhandledProtocolError> {
+        unsafe { *self.ptr_at_mut(0)? = reply_eth(origin) };
+        for (i, tag) in origin.vlan.iter().enumerate() {
+            let offset = EthHdr::LEN + i * VLAN_HDR_LEN;
+            if offset < pac.l3_offset {
+                unsafe { *self.ptr_at_mut(offset)? = *tag };
+            }
+        }
+        if pac.is_tcp() {
+            self.write_rst(pac, origin)
+        } else {
+            self.write_unreachable(pac)
+        }
+    }
+
+    #[inline(always)]
+    fn write_rst(&self, pac: &ParseResult, origin: &Origin) -> Result<(), UnhandledProtocolError> {
+        let mut tcphdr = TcpHdr {
+            source: pac.destination_port.to_be(),
+            dest: pac.source_port.to_be(),
+            seq: 0,
+            ack_seq: 0,
+            _bitfield_align_1: [],
+            _bitfield_1: TcpHdr::new_bitfield_1(0, 5, 0, 0, 1, 0, 0, 0, 0, 0),
+            window: 0,
+            check: 0,
+            urg_ptr: 0,
+        };
+        // RFC 793: на пакет с ACK отвечаем RST с его номером, иначе RST+ACK на весь сегмент
+        if origin.ack {
+            tcphdr.seq = origin.ack_seq.to_be();
+        } else {
+            tcphdr.ack_seq = origin.seq.wrapping_add(origin.payload_len).to_be();
+            tcphdr.set_ack(1);
+        }
+        let sum = pseudo_csum(pac, IpProto::Tcp, TcpHdr::LEN);
+        tcphdr.check = csum_fold(csum_add(sum, &tcphdr));
+
+        if pac.v4 {
+            unsafe {
+                *self.ptr_at_mut(pac.l3_offset)? = reply_ipv4(pac, IpProto::Tcp, TcpHdr::LEN);
+                *self.ptr_at_mut(pac.l3_offset + Ipv4Hdr::LEN)? = tcphdr;
+            }
+        } else {
+            unsafe {
+                *self.ptr_at_mut(pac.l3_offset)? = reply_ipv6(pac, IpProto::Tcp, TcpHdr::LEN);
+                *self.ptr_at_mut(pac.l3_offset + Ipv6Hdr::LEN)? = tcphdr;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn write_unreachable(&self, pac: &ParseResult) -> Result<(), UnhandledProtocolError> {
+        let mut icmphdr = IcmpHdr {
+            type_: 0,
+            code: 0,
+            checksum: 0,
+            un: IcmpHdrUn { reserved: [0; 4] },
+        };
+
+        if pac.v4 {
+            let offset = pac.l3_offset + Ipv4Hdr::LEN + IcmpHdr::LEN;
+            let quote: [u16; QUOTE_V4 / 2] = unsafe { *self.ptr_at_u(offset)? };
+            icmphdr.type_ = ICMP_DEST_UNREACH;
+            icmphdr.code = ICMP_PORT_UNREACH;
+            icmphdr.checksum = csum_fold(csum_add(csum_add(0, &icmphdr), &quote));
+            unsafe {
+                *self.ptr_at_mut(pac.l3_offset)? =
+                    reply_ipv4(pac, IpProto::Icmp, IcmpHdr::LEN + QUOTE_V4);
+                *self.ptr_at_mut(pac.l3_offset + Ipv4Hdr::LEN)? = icmphdr;
+            }
+        } else {
+            let offset = pac.l3_offset + Ipv6Hdr::LEN + IcmpHdr::LEN;
+            let quote: [u16; QUOTE_V6 / 2] = unsafe { *self.ptr_at_u(offset)? };
+            icmphdr.type_ = ICMPV6_DEST_UNREACH;
+            icmphdr.code = ICMPV6_PORT_UNREACH;
+            let sum = pseudo_csum(pac, IpProto::Ipv6Icmp, IcmpHdr::LEN + QUOTE_V6);
+            icmphdr.checksum = csum_fold(csum_add(csum_add(sum, &icmphdr), &quote));
+            unsafe {
+                *self.ptr_at_mut(pac.l3_offset)? =
+                    reply_ipv6(pac, IpProto::Ipv6Icmp, IcmpHdr::LEN + QUOTE_V6);
+                *self.ptr_at_mut(pac.l3_offset + Ipv6Hdr::LEN)? = icmphdr;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Сколько добавить перед IP заголовком и итоговая длина ответа
+#[inline(always)]
+fn reply_size(pac: &ParseResult) -> (usize, usize) {
+    let ip_len = if pac.v4 { Ipv4Hdr::LEN } else { Ipv6Hdr::LEN };
+    if pac.is_tcp() {
+        return (0, pac.l3_offset + ip_len + TcpHdr::LEN);
+    }
+    let quote = if pac.v4 { QUOTE_V4 } else { QUOTE_V6 };
+    let head = ip_len + IcmpHdr::LEN;
+    (head, pac.l3_offset + head + quote)
+}
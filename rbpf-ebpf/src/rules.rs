@@ -1,32 +1,223 @@
 use crate::ip::parser_result::ParseResult;
+use crate::logs::now_ns;
+use crate::{config, limits, logs};
+use aya_ebpf::helpers::bpf_loop;
 use aya_ebpf::macros::map;
-use aya_ebpf::maps::HashMap;
+use aya_ebpf::maps::{Array, HashMap, PerCpuHashMap};
+use core::ffi::c_void;
+use rbpf_common::config::FEATURE_RULE_STATS;
+use rbpf_common::logs::MONITOR;
 use rbpf_common::rules::Action;
-use rbpf_common::rules::Rule;
+use rbpf_common::rules::{
+    CLASS_PROTO_ANY, MAX_CANDIDATES, MAX_RULE_CLASSES, MAX_RULES, Rule, RuleCandidates, RuleClass,
+    RuleStats, TUNNEL_INNER, TUNNEL_OUTER,
+};
 
-const MAX_ENTRIES: u32 = 512;
+// Набор правил хранится в двух поколениях: loader пишет неактивное и одной записью
+// в RULES_GENERATION делает его активным, пакет не видит наполовину записанных правил
+#[map]
+static RULES_GENERATION: Array<u32> = Array::with_max_entries(1, 0);
+
+// Правила в порядке проверки
+#[map]
+static RULES_0: Array<Rule> = Array::with_max_entries(MAX_RULES, 0);
+#[map]
+static RULES_1: Array<Rule> = Array::with_max_entries(MAX_RULES, 0);
+
+// Класс пакета -> отрезок RULES_CANDIDATES того же поколения с номерами правил,
+// которые могут подойти
+#[map]
+static RULES_CLASSES_0: HashMap<RuleClass, RuleCandidates> =
+    HashMap::with_max_entries(MAX_RULE_CLASSES, 0);
+#[map]
+static RULES_CLASSES_1: HashMap<RuleClass, RuleCandidates> =
+    HashMap::with_max_entries(MAX_RULE_CLASSES, 0);
+
+#[map]
+static RULES_CANDIDATES_0: Array<u32> = Array::with_max_entries(MAX_CANDIDATES, 0);
+#[map]
+static RULES_CANDIDATES_1: Array<u32> = Array::with_max_entries(MAX_CANDIDATES, 0);
 
 #[map]
-static RULES: HashMap<u32, Rule> = HashMap::with_max_entries(MAX_ENTRIES, 0);
+static RULES_STATS: PerCpuHashMap<u32, RuleStats> = PerCpuHashMap::with_max_entries(MAX_RULES, 0);
+
+// Состояние обхода кандидатов в bpf_loop
+struct CheckState {
+    pac: *const ParseResult,
+    inner: *const ParseResult,
+    generation: u32,
+    start: u32,
+    action: Action,
+    rule_id: u32,
+    // Правило сработало на внутренний пакет туннеля
+    on_inner: bool,
+}
+
+/// Действие, id правила и признак того, что правило сработало на внутренний пакет туннеля.
+#[inline(always)]
+pub fn check_rule(pac: &ParseResult, inner: Option<&ParseResult>) -> (Action, u32, bool) {
+    // Поколение читается один раз, весь пакет проверяется одним набором правил
+    let generation = match RULES_GENERATION.get(0) {
+        Some(generation) => *generation,
+        None => 0,
+    };
+    let candidates = match candidates(pac, generation) {
+        Some(candidates) => candidates,
+        None => return (Action::Pipe, 0, false),
+    };
+    let mut state = CheckState {
+        pac,
+        inner: match inner {
+            Some(inner) => inner,
+            None => core::ptr::null(),
+        },
+        generation,
+        start: candidates.start,
+        action: Action::Pipe,
+        rule_id: 0,
+        on_inner: false,
+    };
+    unsafe {
+        bpf_loop(
+            candidates.len,
+            check_candidate as *mut c_void,
+            &mut state as *mut CheckState as *mut c_void,
+            0,
+        );
+    }
+    (state.action, state.rule_id, state.on_inner)
+}
+
+// Сначала список для порта назначения, затем для протокола, затем общий
+#[inline(always)]
+fn candidates(pac: &ParseResult, generation: u32) -> Option<RuleCandidates> {
+    let classes = if generation == 0 {
+        &RULES_CLASSES_0
+    } else {
+        &RULES_CLASSES_1
+    };
+    let mut class = RuleClass {
+        proto: pac.proto as u8,
+        input: pac.input,
+        port: if pac.has_ports() {
+            pac.destination_port
+        } else {
+            0
+        },
+    };
+    if class.port != 0 {
+        if let Some(candidates) = unsafe { classes.get(&class) } {
+            return Some(*candidates);
+        }
+        class.port = 0;
+    }
+    if let Some(candidates) = unsafe { classes.get(&class) } {
+        return Some(*candidates);
+    }
+    class.proto = CLASS_PROTO_ANY;
+    unsafe { classes.get(&class) }.copied()
+}
+
+// 0 - проверить следующего кандидата, 1 - решение принято
+unsafe extern "C" fn check_candidate(index: u64, ctx: *mut c_void) -> i64 {
+    let state = unsafe { &mut *(ctx as *mut CheckState) };
+    let (candidates, rules) = if state.generation == 0 {
+        (&RULES_CANDIDATES_0, &RULES_0)
+    } else {
+        (&RULES_CANDIDATES_1, &RULES_1)
+    };
+    let rule_index = match candidates.get(state.start + index as u32) {
+        Some(rule_index) => *rule_index,
+        None => return 1,
+    };
+    let rule = match rules.get(rule_index) {
+        Some(rule) => rule,
+        None => return 1,
+    };
+    let pac = unsafe { &*state.pac };
+    let inner = unsafe { state.inner.as_ref() };
+
+    let (mut res, matched) = rule_action(rule, pac, inner);
+    if res == Action::Pipe {
+        return 0;
+    }
+    // В пределах лимита пакет пропускается как по ok, monitor пишет в лог только превышение
+    let limited = res == Action::Limit || (res == Action::Monitor && rule.limit);
+    if limited && !limits::is_over_limit(rule, matched) {
+        if res == Action::Monitor {
+            return 0;
+        }
+        res = Action::Ok;
+    }
+    if config::is_enabled(FEATURE_RULE_STATS) {
+        count(rule.rule_id, pac);
+    }
+    // Правило в режиме monitor только пишет в лог, пакет проверяется дальше
+    if res == Action::Monitor {
+        logs::send_from_rule(monitor_message(rule), rule.rule_id, pac, MONITOR);
+        return 0;
+    }
+    state.action = res;
+    state.rule_id = rule.rule_id;
+    state.on_inner = !core::ptr::eq(matched, pac);
+    1
+}
 
+// Правило сверяется с внешним и / или внутренним пакетом туннеля, возвращает подошедший
 #[inline(always)]
-pub fn check_rule(pac: &ParseResult) -> (Action, u32) {
-    for index in 0..=MAX_ENTRIES {
-        let rule = unsafe { RULES.get(&index) };
-        return match rule {
-            Some(rule) => {
-                if pac.not_my_rule(rule) {
-                    continue;
-                }
-
-                let res = pac.to_action(rule);
-                if res == Action::Pipe {
-                    continue;
-                }
-                (res, rule.rule_id)
-            }
-            None => (Action::Pipe, 0),
-        };
-    }
-    (Action::Pipe, 0)
+fn rule_action<'a>(
+    rule: &Rule,
+    pac: &'a ParseResult,
+    inner: Option<&'a ParseResult>,
+) -> (Action, &'a ParseResult) {
+    if rule.tunnel != TUNNEL_INNER && !pac.not_my_rule(rule) {
+        let res = pac.to_action(rule);
+        if res != Action::Pipe {
+            return (res, pac);
+        }
+    }
+    if rule.tunnel != TUNNEL_OUTER
+        && let Some(inner) = inner
+        && !inner.not_my_rule(rule)
+    {
+        return (inner.to_action(rule), inner);
+    }
+    (Action::Pipe, pac)
+}
+
+// Что сделало бы правило без monitor
+#[inline(always)]
+fn monitor_message(rule: &Rule) -> &'static str {
+    if rule.reject {
+        "MONITOR REJECT"
+    } else if rule.drop {
+        "MONITOR DROP"
+    } else if rule.ok {
+        "MONITOR OK"
+    } else if rule.limit {
+        "MONITOR LIMIT"
+    } else {
+        "MONITOR"
+    }
+}
+
+#[inline(always)]
+fn count(rule_id: u32, pac: &ParseResult) {
+    let now = now_ns();
+    match RULES_STATS.get_ptr_mut(&rule_id) {
+        Some(stats) => {
+            let stats = unsafe { &mut *stats };
+            stats.packets += 1;
+            stats.bytes += pac.len as u64;
+            stats.last_hit_ns = now;
+        }
+        None => {
+            let stats = RuleStats {
+                packets: 1,
+                bytes: pac.len as u64,
+                last_hit_ns: now,
+            };
+            let _ = RULES_STATS.insert(&rule_id, &stats, 0);
+        }
+    }
 }
@@ -0,0 +1,26 @@
+use aya_ebpf::macros::map;
+use aya_ebpf::maps::LpmTrie;
+use aya_ebpf::maps::lpm_trie::Key;
+use rbpf_common::sets::{IP_SET_ID_BITS, IpSetV4, IpSetV6};
+
+const MAX_SET_ENTRIES_V4: u32 = 262144;
+const MAX_SET_ENTRIES_V6: u32 = 65536;
+
+// Все сеты лежат в одном trie, ключ начинается с id сета
+#[map]
+static IP_SET_V4: LpmTrie<IpSetV4, u8> = LpmTrie::with_max_entries(MAX_SET_ENTRIES_V4, 0);
+
+#[map]
+static IP_SET_V6: LpmTrie<IpSetV6, u8> = LpmTrie::with_max_entries(MAX_SET_ENTRIES_V6, 0);
+
+#[inline(always)]
+pub fn contains_v4(set_id: u32, addr: u32) -> bool {
+    let key = Key::new(IP_SET_ID_BITS + 32, IpSetV4::new(set_id, addr));
+    IP_SET_V4.get(&key).is_some()
+}
+
+#[inline(always)]
+pub fn contains_v6(set_id: u32, addr: u128) -> bool {
+    let key = Key::new(IP_SET_ID_BITS + 128, IpSetV6::new(set_id, addr));
+    IP_SET_V6.get(&key).is_some()
+}
@@ -12,7 +12,8 @@ use poem::{
 };
 use poem_openapi::{OpenApi, OpenApiService, payload::Json};
 use rbpf_common::logs::logs::LogMessageSerialized;
-use rbpf_common::rules::rules::{Control, ControlAction, RuleWithName};
+use rbpf_common::rules::rules::{Control, ControlAction, RuleStatsSerialized, RuleWithName};
+use rbpf_common::sets::sets::IpSet;
 use serde_json::from_slice;
 use std::collections::HashMap;
 use std::ops::Deref;
@@ -36,9 +37,34 @@ impl Api {
         let con = Control {
             action: ControlAction::Reload,
             rule: RuleWithName::from_empty(),
+            ip_set: IpSet::from_empty(),
         };
         match self.send_command(state, con).await {
-            Ok(_) => Json("Reload signal sent".to_string()),
+            // Если правила не загрузились, loader отвечает текстом ошибки
+            Ok(socket) => {
+                let buffer = self.read_response(socket).await;
+                Json(from_slice::<String>(&buffer).unwrap_or("Reload signal sent".to_string()))
+            }
+            Err(e) => Json(e.to_string()),
+        }
+    }
+
+    #[oai(path = "/tunables/reload", method = "post")]
+    async fn reload_tunables(&self, state: Data<&ApiState>) -> Json<String> {
+        let con = Control {
+            action: ControlAction::ReloadTunables,
+            rule: RuleWithName::from_empty(),
+            ip_set: IpSet::from_empty(),
+        };
+        match self.send_command(state, con).await {
+            // loader отвечает "Tunables reloaded" или текстом ошибки
+            Ok(socket) => {
+                let buffer = self.read_response(socket).await;
+                Json(
+                    from_slice::<String>(&buffer)
+                        .unwrap_or("Reload tunables signal sent".to_string()),
+                )
+            }
             Err(e) => Json(e.to_string()),
         }
     }
@@ -48,6 +74,7 @@ impl Api {
         let con = Control {
             action: ControlAction::GetRules,
             rule: RuleWithName::from_empty(),
+            ip_set: IpSet::from_empty(),
         };
         self.send_and_read(state, con).await
     }
@@ -62,6 +89,7 @@ impl Api {
         let con = Control {
             action: ControlAction::CreateRule,
             rule: wrule.clone(),
+            ip_set: IpSet::from_empty(),
         };
         self.send_and_read(state, con).await
     }
@@ -77,10 +105,79 @@ impl Api {
         let con = Control {
             action: ControlAction::UpdateRule,
             rule: wrule.clone(),
+            ip_set: IpSet::from_empty(),
         };
         self.send_and_read(state, con).await
     }
 
+    #[oai(path = "/rules/:id/stats", method = "get")]
+    async fn get_rule_stats(
+        &self,
+        state: Data<&ApiState>,
+        id: Path<u32>,
+    ) -> Json<RuleStatsSerialized> {
+        let mut rule = RuleWithName::from_empty();
+        rule.rule_id = id.0;
+        let con = Control {
+            action: ControlAction::GetRuleStats,
+            rule,
+            ip_set: IpSet::from_empty(),
+        };
+        let empty = RuleStatsSerialized {
+            rule_id: id.0,
+            packets: 0,
+            bytes: 0,
+            last_hit_ns: 0,
+            last_hit: 0,
+        };
+        match self.send_command(state, con).await {
+            Ok(socket) => {
+                let buffer = self.read_response(socket).await;
+                Json(from_slice::<RuleStatsSerialized>(&buffer).unwrap_or(empty))
+            }
+            Err(_) => Json(empty),
+        }
+    }
+
+    #[oai(path = "/sets", method = "get")]
+    async fn get_sets(&self, state: Data<&ApiState>) -> Json<Vec<IpSet>> {
+        let con = Control {
+            action: ControlAction::GetSets,
+            rule: RuleWithName::from_empty(),
+            ip_set: IpSet::from_empty(),
+        };
+        self.send_and_read_sets(state, con).await
+    }
+
+    #[oai(path = "/sets/:name", method = "put")]
+    async fn update_set(
+        &self,
+        state: Data<&ApiState>,
+        name: Path<String>,
+        ip_set: Json<IpSet>,
+    ) -> Json<Vec<IpSet>> {
+        let mut wset: IpSet = ip_set.deref().clone();
+        wset.name = name.0;
+        let con = Control {
+            action: ControlAction::UpdateSet,
+            rule: RuleWithName::from_empty(),
+            ip_set: wset,
+        };
+        self.send_and_read_sets(state, con).await
+    }
+
+    #[oai(path = "/sets/:name", method = "delete")]
+    async fn delete_set(&self, state: Data<&ApiState>, name: Path<String>) -> Json<Vec<IpSet>> {
+        let mut wset = IpSet::from_empty();
+        wset.name = name.0;
+        let con = Control {
+            action: ControlAction::DeleteSet,
+            rule: RuleWithName::from_empty(),
+            ip_set: wset,
+        };
+        self.send_and_read_sets(state, con).await
+    }
+
     async fn send_command(
         &self,
         state: Data<&ApiState>,
@@ -89,6 +186,8 @@ impl Api {
         let mut stream = UnixStream::connect(&state.control_socket_path).await?;
         let serialized = serde_json::to_vec(&command)?;
         stream.write_all(&serialized).await?;
+        // loader читает команду до EOF
+        stream.shutdown().await?;
         Ok(stream)
     }
 
@@ -98,16 +197,8 @@ impl Api {
         command: Control,
     ) -> Json<Vec<RuleWithName>> {
         match self.send_command(state, command).await {
-            Ok(mut socket) => {
-                let mut buffer = Vec::new();
-                let mut chunk = [0u8; 1024];
-
-                while let Ok(n) = socket.read(&mut chunk).await {
-                    if n == 0 {
-                        break;
-                    }
-                    buffer.extend_from_slice(&chunk[..n]);
-                }
+            Ok(socket) => {
+                let buffer = self.read_response(socket).await;
                 match from_slice::<HashMap<u32, RuleWithName>>(&buffer) {
                     Ok(rules) => Json(rules.into_values().collect::<Vec<RuleWithName>>()),
                     Err(_) => Json(Vec::new()),
@@ -116,6 +207,36 @@ impl Api {
             Err(_) => Json(Vec::new()),
         }
     }
+
+    async fn send_and_read_sets(
+        &self,
+        state: Data<&ApiState>,
+        command: Control,
+    ) -> Json<Vec<IpSet>> {
+        match self.send_command(state, command).await {
+            Ok(socket) => {
+                let buffer = self.read_response(socket).await;
+                match from_slice::<HashMap<String, IpSet>>(&buffer) {
+                    Ok(sets) => Json(sets.into_values().collect::<Vec<IpSet>>()),
+                    Err(_) => Json(Vec::new()),
+                }
+            }
+            Err(_) => Json(Vec::new()),
+        }
+    }
+
+    async fn read_response(&self, mut socket: UnixStream) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 1024];
+
+        while let Ok(n) = socket.read(&mut chunk).await {
+            if n == 0 {
+                break;
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+        buffer
+    }
 }
 
 pub async fn http_ws_server(settings: Settings) -> anyhow::Result<()> {
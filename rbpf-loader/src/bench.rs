@@ -0,0 +1,147 @@
+use anyhow::anyhow;
+use aya::Ebpf;
+use aya::programs::Xdp;
+use clap::Parser;
+use rbpf_common::rules::Rule;
+use rbpf_common::rules::rules::RuleWithName;
+use rbpf_loader::rules;
+use std::os::fd::{AsFd, AsRawFd};
+
+// Проверка правил без загрузки на интерфейс: пакет прогоняется через xdp_ingress
+// BPF_PROG_TEST_RUN, ядро возвращает среднее время одного прогона
+const XDP: &str = "xdp_ingress";
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+const FIRST_PORT: u16 = 10000;
+
+#[derive(Debug, Parser)]
+struct Opt {
+    // Сколько правил загрузить, каждое на свой порт назначения
+    #[clap(short, long, default_value = "2048")]
+    rules: u16,
+
+    #[clap(long, default_value = "100000")]
+    repeat: u32,
+
+    #[clap(long, default_value = "./rbpf.o")]
+    ebpf: String,
+}
+
+// union bpf_attr для BPF_PROG_TEST_RUN
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+    flags: u32,
+    cpu: u32,
+    batch_size: u32,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let opt = Opt::parse();
+    let rlim = libc::rlimit {
+        rlim_cur: libc::RLIM_INFINITY,
+        rlim_max: libc::RLIM_INFINITY,
+    };
+    unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+
+    // Порты правил FIRST_PORT..=FIRST_PORT + rules - 1 должны помещаться в u16
+    if opt.rules == 0 || opt.rules > u16::MAX - FIRST_PORT + 1 {
+        return Err(anyhow!(
+            "--rules must be in 1..={}",
+            u16::MAX - FIRST_PORT + 1
+        ));
+    }
+
+    let data = std::fs::read(&opt.ebpf)?;
+    let mut ebpf = Ebpf::load(&data)?;
+    let program: &mut Xdp = ebpf.program_mut(XDP).unwrap().try_into()?;
+    program.load()?;
+
+    let rules = make_rules(opt.rules);
+    let last_port = FIRST_PORT + opt.rules - 1;
+    println!(
+        "{} rules, {} runs per packet, ns per packet:",
+        opt.rules, opt.repeat
+    );
+    println!("{:<10}{:>12}{:>12}", "", "last rule", "no rule");
+    // one list - все правила одним списком кандидатов, каждый пакет проверяется всеми правилами
+    for (name, linear) in [("one list", true), ("indexed", false)] {
+        rules::write_bpf_maps(&mut ebpf, &rules, linear)?;
+        let hit = test_run(&ebpf, &tcp_packet(last_port), opt.repeat)?;
+        let miss = test_run(&ebpf, &tcp_packet(FIRST_PORT - 1), opt.repeat)?;
+        println!("{:<10}{:>12}{:>12}", name, hit, miss);
+    }
+    Ok(())
+}
+
+fn make_rules(count: u16) -> Vec<Rule> {
+    (0..count)
+        .map(|index| {
+            let mut rule = RuleWithName::from_empty();
+            rule.rule_id = index as u32 + 1;
+            rule.on = true;
+            rule.drop = true;
+            rule.v4 = true;
+            rule.tcp = true;
+            rule.input = true;
+            rule.destination_port_start = FIRST_PORT + index;
+            rule.destination_port_end = FIRST_PORT + index;
+            rule.to_common_rule()
+        })
+        .collect()
+}
+
+// Ethernet + IPv4 + TCP SYN на 10.0.0.2:port
+fn tcp_packet(port: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 54];
+    packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    packet[14] = 0x45;
+    packet[16..18].copy_from_slice(&40u16.to_be_bytes());
+    packet[22] = 64;
+    packet[23] = libc::IPPROTO_TCP as u8;
+    packet[26..30].copy_from_slice(&[10, 0, 0, 1]);
+    packet[30..34].copy_from_slice(&[10, 0, 0, 2]);
+    packet[34..36].copy_from_slice(&40000u16.to_be_bytes());
+    packet[36..38].copy_from_slice(&port.to_be_bytes());
+    packet[46] = 0x50;
+    packet[47] = 0x02;
+    packet
+}
+
+fn test_run(ebpf: &Ebpf, packet: &[u8], repeat: u32) -> anyhow::Result<u32> {
+    let program: &Xdp = ebpf.program(XDP).unwrap().try_into()?;
+    let mut attr = TestRunAttr {
+        prog_fd: program.fd()?.as_fd().as_raw_fd() as u32,
+        data_size_in: packet.len() as u32,
+        data_in: packet.as_ptr() as u64,
+        repeat,
+        ..Default::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_TEST_RUN,
+            &mut attr as *mut TestRunAttr,
+            size_of::<TestRunAttr>() as u32,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "BPF_PROG_TEST_RUN failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(attr.duration)
+}
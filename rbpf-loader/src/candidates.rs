@@ -0,0 +1,334 @@
+use crate::rules::Capacity;
+use log::warn;
+use rbpf_common::ipproto::IP_PROTO_ANY;
+use rbpf_common::rules::{CLASS_PROTO_ANY, Rule, RuleCandidates, RuleClass, TUNNEL_OUTER};
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+// Диапазон портов назначения не шире этого раскладывается по спискам отдельных портов,
+// более широкий попадает в общий список протокола
+const MAX_PORT_SPAN: u16 = 64;
+
+const PORT_PROTOS: [u8; 5] = [
+    libc::IPPROTO_TCP as u8,
+    libc::IPPROTO_UDP as u8,
+    libc::IPPROTO_SCTP as u8,
+    libc::IPPROTO_DCCP as u8,
+    libc::IPPROTO_UDPLITE as u8,
+];
+
+// Содержимое RULES_CLASSES и RULES_CANDIDATES
+pub struct Candidates {
+    pub classes: Vec<(RuleClass, RuleCandidates)>,
+    pub candidates: Vec<u32>,
+}
+
+impl Candidates {
+    pub fn fits(&self, capacity: &Capacity) -> bool {
+        self.classes.len() <= capacity.classes as usize
+            && self.candidates.len() <= capacity.candidates as usize
+    }
+}
+
+/// Раскладывает правила (индекс в срезе = индекс в RULES) по спискам кандидатов.
+/// В список класса попадает каждое правило, которое может подойти пакету этого класса,
+/// порядок правил сохраняется. linear - один список из всех правил на направление.
+pub fn build(rules: &[Rule], linear: bool) -> Candidates {
+    let mut classes: BTreeMap<(u8, bool, u16), Vec<u32>> = BTreeMap::new();
+
+    for rule in rules {
+        for input in directions(rule) {
+            if linear || is_any_proto(rule) {
+                classes.entry((CLASS_PROTO_ANY, input, 0)).or_default();
+                continue;
+            }
+            for proto in protos(rule) {
+                classes.entry((proto, input, 0)).or_default();
+                if let Some(ports) = ports(rule, proto) {
+                    for port in ports {
+                        classes.entry((proto, input, port)).or_default();
+                    }
+                }
+            }
+        }
+    }
+
+    for (index, rule) in rules.iter().enumerate() {
+        for (&(proto, input, port), list) in classes.iter_mut() {
+            if linear || is_candidate(rule, proto, input, port) {
+                list.push(index as u32);
+            }
+        }
+    }
+
+    let mut result = Candidates {
+        classes: Vec::new(),
+        candidates: Vec::new(),
+    };
+    for ((proto, input, port), list) in classes {
+        let range = RuleCandidates {
+            start: result.candidates.len() as u32,
+            len: list.len() as u32,
+        };
+        result
+            .classes
+            .push((RuleClass { proto, input, port }, range));
+        result.candidates.extend(list);
+    }
+    result
+}
+
+/// Как build, но если списки не помещаются в map - один список из всех правил на направление.
+pub fn build_within(rules: &[Rule], linear: bool, capacity: &Capacity) -> Candidates {
+    let index = build(rules, linear);
+    if index.fits(capacity) {
+        return index;
+    }
+    warn!(
+        "Rule candidates do not fit in maps ({} classes, {} candidates), use linear scan",
+        index.classes.len(),
+        index.candidates.len()
+    );
+    build(rules, true)
+}
+
+fn is_candidate(rule: &Rule, proto: u8, input: bool, port: u16) -> bool {
+    if !directions(rule).contains(&input) {
+        return false;
+    }
+    if is_any_proto(rule) {
+        return true;
+    }
+    if proto == CLASS_PROTO_ANY || !protos(rule).contains(&proto) {
+        return false;
+    }
+    match ports(rule, proto) {
+        Some(ports) => port != 0 && ports.contains(&port),
+        None => true,
+    }
+}
+
+// Правило для внутреннего пакета туннеля проверяется при любом протоколе внешнего
+fn is_any_proto(rule: &Rule) -> bool {
+    rule.tunnel != TUNNEL_OUTER
+}
+
+fn directions(rule: &Rule) -> Vec<bool> {
+    let mut directions = Vec::new();
+    if rule.input {
+        directions.push(true);
+    }
+    if rule.output {
+        directions.push(false);
+    }
+    directions
+}
+
+fn protos(rule: &Rule) -> Vec<u8> {
+    if rule.ip_proto != IP_PROTO_ANY {
+        return vec![rule.ip_proto];
+    }
+    let flags = [
+        (rule.tcp, libc::IPPROTO_TCP),
+        (rule.udp, libc::IPPROTO_UDP),
+        (rule.icmp, libc::IPPROTO_ICMP),
+        (rule.icmpv6, libc::IPPROTO_ICMPV6),
+        (rule.sctp, libc::IPPROTO_SCTP),
+        (rule.dccp, libc::IPPROTO_DCCP),
+        (rule.udplite, libc::IPPROTO_UDPLITE),
+    ];
+    flags
+        .into_iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, proto)| proto as u8)
+        .collect()
+}
+
+// Порты назначения, под которые правило кладётся в отдельные списки
+fn ports(rule: &Rule, proto: u8) -> Option<RangeInclusive<u16>> {
+    let start = rule.destination_port_start;
+    let end = rule.destination_port_end;
+    if !PORT_PROTOS.contains(&proto) || start == 0 || end < start || end - start >= MAX_PORT_SPAN {
+        return None;
+    }
+    Some(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rbpf_common::rules::rules::RuleWithName;
+    use rbpf_common::rules::{TUNNEL_BOTH, TUNNEL_INNER};
+
+    const TCP: u8 = libc::IPPROTO_TCP as u8;
+    const UDP: u8 = libc::IPPROTO_UDP as u8;
+    const ICMP: u8 = libc::IPPROTO_ICMP as u8;
+
+    fn rule(setup: impl FnOnce(&mut RuleWithName)) -> Rule {
+        let mut rule = RuleWithName::from_empty();
+        rule.on = true;
+        rule.drop = true;
+        rule.v4 = true;
+        rule.input = true;
+        setup(&mut rule);
+        rule.to_common_rule()
+    }
+
+    fn tcp_port(start: u16, end: u16) -> Rule {
+        rule(|rule| {
+            rule.tcp = true;
+            rule.destination_port_start = start;
+            rule.destination_port_end = end;
+        })
+    }
+
+    // Класс -> индексы правил его списка
+    fn lists(index: &Candidates) -> BTreeMap<(u8, bool, u16), Vec<u32>> {
+        index
+            .classes
+            .iter()
+            .map(|(class, range)| {
+                let start = range.start as usize;
+                let list = index.candidates[start..start + range.len as usize].to_vec();
+                ((class.proto, class.input, class.port), list)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn narrow_port_ranges_get_own_classes() {
+        let rules = [
+            tcp_port(80, 80),
+            tcp_port(1000, 1100),
+            tcp_port(8000, 8000 + MAX_PORT_SPAN - 1),
+            tcp_port(9000, 9000 + MAX_PORT_SPAN),
+        ];
+        let lists = lists(&build(&rules, false));
+
+        // Широкие диапазоны только в общем списке протокола и в каждом списке порта
+        assert_eq!(lists[&(TCP, true, 0)], vec![1, 3]);
+        assert_eq!(lists[&(TCP, true, 80)], vec![0, 1, 3]);
+        assert_eq!(lists[&(TCP, true, 8000)], vec![1, 2, 3]);
+        assert_eq!(lists[&(TCP, true, 8000 + MAX_PORT_SPAN - 1)], vec![1, 2, 3]);
+        assert!(!lists.contains_key(&(TCP, true, 8000 + MAX_PORT_SPAN)));
+        assert!(!lists.contains_key(&(TCP, true, 1000)));
+        assert!(!lists.contains_key(&(TCP, true, 9000)));
+        assert_eq!(lists.len(), 2 + MAX_PORT_SPAN as usize);
+    }
+
+    #[test]
+    fn ports_ignored_for_protocols_without_ports() {
+        let rules = [rule(|rule| {
+            rule.icmp = true;
+            rule.destination_port_start = 80;
+            rule.destination_port_end = 80;
+        })];
+        let lists = lists(&build(&rules, false));
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[&(ICMP, true, 0)], vec![0]);
+    }
+
+    #[test]
+    fn tunnel_rules_copied_into_every_class() {
+        let rules = [
+            tcp_port(80, 80),
+            rule(|rule| {
+                rule.udp = true;
+                rule.tunnel = TUNNEL_INNER;
+            }),
+            rule(|rule| {
+                rule.tcp = true;
+                rule.udp = true;
+                rule.output = true;
+            }),
+            rule(|rule| {
+                rule.icmp = true;
+                rule.tunnel = TUNNEL_BOTH;
+            }),
+        ];
+        let lists = lists(&build(&rules, false));
+
+        assert_eq!(lists[&(CLASS_PROTO_ANY, true, 0)], vec![1, 3]);
+        assert_eq!(lists[&(TCP, true, 0)], vec![1, 2, 3]);
+        assert_eq!(lists[&(TCP, true, 80)], vec![0, 1, 2, 3]);
+        assert_eq!(lists[&(UDP, true, 0)], vec![1, 2, 3]);
+        assert_eq!(lists[&(UDP, false, 0)], vec![2]);
+        assert_eq!(lists[&(TCP, false, 0)], vec![2]);
+        // Правила туннеля только на вход, отдельного класса ANY на выход нет
+        assert!(!lists.contains_key(&(CLASS_PROTO_ANY, false, 0)));
+        assert!(!lists.contains_key(&(ICMP, true, 0)));
+    }
+
+    #[test]
+    fn ip_proto_overrides_flags() {
+        let rules = [rule(|rule| {
+            rule.tcp = true;
+            rule.ip_proto = libc::IPPROTO_GRE as u8;
+        })];
+        let lists = lists(&build(&rules, false));
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[&(libc::IPPROTO_GRE as u8, true, 0)], vec![0]);
+    }
+
+    #[test]
+    fn rule_order_preserved() {
+        let rules: Vec<Rule> = (0..20)
+            .map(|i| match i % 3 {
+                0 => tcp_port(80, 80),
+                1 => tcp_port(79, 81),
+                _ => rule(|rule| rule.tunnel = TUNNEL_BOTH),
+            })
+            .collect();
+        let index = build(&rules, false);
+        for (class, list) in lists(&index) {
+            assert!(list.windows(2).all(|pair| pair[0] < pair[1]), "{class:?}");
+        }
+        let expected: Vec<u32> = (0..20).collect();
+        assert_eq!(lists(&index)[&(TCP, true, 80)], expected);
+    }
+
+    #[test]
+    fn linear_has_one_list_per_direction() {
+        let rules = [
+            tcp_port(80, 80),
+            rule(|rule| {
+                rule.udp = true;
+                rule.input = false;
+                rule.output = true;
+            }),
+        ];
+        let lists = lists(&build(&rules, true));
+        assert_eq!(lists.len(), 2);
+        assert_eq!(lists[&(CLASS_PROTO_ANY, true, 0)], vec![0, 1]);
+        assert_eq!(lists[&(CLASS_PROTO_ANY, false, 0)], vec![0, 1]);
+    }
+
+    #[test]
+    fn linear_fallback_when_lists_overflow() {
+        let rules: Vec<Rule> = (0..10).map(|i| tcp_port(100 + i, 100 + i)).collect();
+        let indexed = build(&rules, false);
+        assert_eq!(indexed.classes.len(), 11);
+
+        let roomy = Capacity {
+            rules: 10,
+            classes: 11,
+            candidates: indexed.candidates.len() as u32,
+        };
+        let index = build_within(&rules, false, &roomy);
+        assert_eq!(lists(&index), lists(&indexed));
+
+        let tight = Capacity {
+            rules: 10,
+            classes: 10,
+            candidates: 1000,
+        };
+        let index = build_within(&rules, false, &tight);
+        assert_eq!(index.classes.len(), 1);
+        let expected: Vec<u32> = (0..10).collect();
+        assert_eq!(lists(&index)[&(CLASS_PROTO_ANY, true, 0)], expected);
+
+        // linear выбирается сразу, даже если индекс поместился бы
+        let index = build_within(&rules, true, &roomy);
+        assert_eq!(index.classes.len(), 1);
+    }
+}
@@ -1,14 +1,16 @@
 use crate::database;
 use crate::rules;
+use crate::sets;
+use crate::settings;
 use crate::settings::Settings;
 use aya::Ebpf;
-use log::info;
+use log::{info, warn};
 use rbpf_common::rules::rules::{Control, ControlAction};
 use std::fs;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
+use tokio::net::{UnixListener, UnixStream};
 
 pub fn change_socket_owner_mode(
     socket_path: &str,
@@ -35,6 +37,15 @@ pub fn change_socket_owner_mode(
     Ok(())
 }
 
+// Ошибка перезагрузки правил не останавливает loader: в ядре остаётся активное поколение,
+// клиенту вместо ответа уходит текст ошибки
+async fn reply_error(socket: &mut UnixStream, error: anyhow::Error) -> anyhow::Result<()> {
+    warn!("Control command failed: {}", error);
+    let json_data = serde_json::to_vec(&error.to_string())?;
+    socket.write_all(&json_data).await?;
+    Ok(())
+}
+
 pub async fn control_loop(settings: Arc<Settings>, ebpf: &mut Ebpf) -> anyhow::Result<()> {
     info!("Starting control loop...");
     if Path::new(&settings.control_socket_path).exists() {
@@ -52,16 +63,47 @@ pub async fn control_loop(settings: Arc<Settings>, ebpf: &mut Ebpf) -> anyhow::R
     loop {
         let (mut socket, _) = control_listener.accept().await?;
 
-        let mut buffer = vec![0; 1024];
-        match socket.read(&mut buffer).await {
+        // Сеты могут быть большими, читаем до закрытия пишущей стороны клиентом
+        let mut buffer = Vec::new();
+        match socket.read_to_end(&mut buffer).await {
             Ok(n) if n > 0 => {
                 let received_data = &buffer[..n];
                 let control = serde_json::from_slice::<Control>(received_data)?;
                 match control.action {
                     ControlAction::Reload => {
-                        rules::load_rules_from_dir(&settings.rules_path).await?;
-                        rules::load_rules_from_db().await?;
-                        rules::make_bpf_maps(ebpf).await?;
+                        sets::load_sets_from_dir(&settings.sets_path).await?;
+                        if settings.db_on {
+                            sets::load_sets_from_db().await?;
+                        }
+                        sets::make_bpf_maps(ebpf).await?;
+
+                        // Новые правила попадают в хранилище, только если помещаются в map
+                        let mut loaded = rules::read_rules_from_dir(&settings.rules_path).await?;
+                        if settings.db_on {
+                            loaded.extend(database::fetch_rules().await?);
+                        }
+                        let mut next = rules::get_rules().await;
+                        for rule in loaded {
+                            next.insert(rule.rule_id, rule);
+                        }
+                        if let Err(e) =
+                            rules::check_capacity(next.values().cloned().collect()).await
+                        {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
+                        rules::set_rules(next).await;
+                        if let Err(e) = rules::reload_rules(ebpf).await {
+                            reply_error(&mut socket, e).await?;
+                        }
+                    }
+                    ControlAction::ReloadTunables => {
+                        if let Err(e) = settings::reload_tunables(&settings, ebpf).await {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
+                        let json_data = serde_json::to_vec("Tunables reloaded")?;
+                        socket.write_all(&json_data).await?;
                     }
                     ControlAction::GetRules => {
                         let rules = rules::get_rules().await;
@@ -69,8 +111,40 @@ pub async fn control_loop(settings: Arc<Settings>, ebpf: &mut Ebpf) -> anyhow::R
                         socket.write_all(&json_data).await?;
                     }
                     ControlAction::UpdateRule => {
+                        let rule_id = control.rule.rule_id;
+                        let prev = rules::get_rules().await;
+                        let switched = rules::is_switch(&control.rule).await;
+                        let mut next = prev.clone();
+                        next.insert(rule_id, control.rule.clone());
+                        if !switched
+                            && let Err(e) =
+                                rules::check_capacity(next.into_values().collect()).await
+                        {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
+                        // Включение / выключение пишется в ядро до изменения хранилища,
+                        // если не вышло - правила перезагружаются целиком
+                        let switched = switched
+                            && match rules::switch_rule(ebpf, rule_id, control.rule.on) {
+                                Ok(switched) => switched,
+                                Err(e) => {
+                                    warn!("Failed to switch rule {}: {}, reload rules", rule_id, e);
+                                    false
+                                }
+                            };
                         rules::change_rule(control.rule.clone()).await;
-                        rules::reload_rules(ebpf).await?;
+                        if !switched && let Err(e) = rules::reload_rules(ebpf).await {
+                            // Хранилище и БД возвращаются к правилам, оставшимся в ядре
+                            if let Some(old) = prev.get(&rule_id)
+                                && old.from_db
+                            {
+                                database::update_rule(old).await;
+                            }
+                            rules::set_rules(prev).await;
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
                         let rules = rules::get_rules().await;
                         let json_data = serde_json::to_vec(&rules)?;
                         socket.write_all(&json_data).await?;
@@ -78,17 +152,71 @@ pub async fn control_loop(settings: Arc<Settings>, ebpf: &mut Ebpf) -> anyhow::R
                     ControlAction::CreateRule => {
                         let mut new_rule = control.rule.clone();
                         new_rule.order = rules::get_rules_len().await;
+                        let mut next: Vec<_> = rules::get_rules().await.into_values().collect();
+                        next.push(new_rule.clone());
+                        if let Err(e) = rules::check_capacity(next).await {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
                         let rule_id = u32::try_from(database::insert_rule(&new_rule).await)?;
                         if rule_id != 0 {
                             new_rule.rule_id = rule_id;
                             rules::set_rule(new_rule.clone()).await;
-                            rules::reload_rules(ebpf).await?;
+                            if let Err(e) = rules::reload_rules(ebpf).await {
+                                reply_error(&mut socket, e).await?;
+                                continue;
+                            }
                             let rules = rules::get_rules().await;
                             let json_data = serde_json::to_vec(&rules)?;
                             socket.write_all(&json_data).await?;
                         }
                         socket.flush().await?;
                     }
+                    ControlAction::GetRuleStats => {
+                        let stats = rules::get_rule_stats(ebpf, control.rule.rule_id)?;
+                        let json_data = serde_json::to_vec(&stats)?;
+                        socket.write_all(&json_data).await?;
+                    }
+                    ControlAction::GetSets => {
+                        let sets = sets::get_sets().await;
+                        let json_data = serde_json::to_vec(&sets)?;
+                        socket.write_all(&json_data).await?;
+                    }
+                    ControlAction::UpdateSet => {
+                        let mut new_set = control.ip_set.clone();
+                        if settings.db_on {
+                            new_set.from_db = database::upsert_set(&new_set).await;
+                        }
+                        let new_set = sets::set_ip_set(new_set).await;
+                        if let Err(e) = sets::load_set(ebpf, &new_set) {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
+                        // Правила, ссылавшиеся на ещё не созданный сет, теперь можно загрузить
+                        if let Err(e) = rules::reload_rules(ebpf).await {
+                            reply_error(&mut socket, e).await?;
+                            continue;
+                        }
+                        let sets = sets::get_sets().await;
+                        let json_data = serde_json::to_vec(&sets)?;
+                        socket.write_all(&json_data).await?;
+                    }
+                    ControlAction::DeleteSet => {
+                        if let Some(old_set) = sets::remove_ip_set(&control.ip_set.name).await {
+                            if old_set.from_db {
+                                database::delete_set(&old_set.name).await;
+                            }
+                            // Без перезагрузки правил сет очищать нельзя, они продолжат на него ссылаться
+                            if let Err(e) = rules::reload_rules(ebpf).await {
+                                reply_error(&mut socket, e).await?;
+                                continue;
+                            }
+                            sets::clear_set(ebpf, old_set.set_id)?;
+                        }
+                        let sets = sets::get_sets().await;
+                        let json_data = serde_json::to_vec(&sets)?;
+                        socket.write_all(&json_data).await?;
+                    }
                 }
             }
             _ => {}
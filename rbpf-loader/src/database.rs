@@ -1,5 +1,6 @@
 use log::{info, warn};
 use rbpf_common::rules::rules::RuleWithName;
+use rbpf_common::sets::sets::IpSet;
 use sqlx::{Row, SqlitePool, migrate::Migrator};
 use std::net::Ipv6Addr;
 use std::path::Path;
@@ -47,7 +48,7 @@ pub async fn fetch_rules() -> anyhow::Result<Vec<RuleWithName>> {
     let rows = sqlx::query(
         r#"
         SELECT
-            rule_name, id as rule_id, "drop", ok, v4, v6, tcp, udp, "on",
+            rule_name, id as rule_id, "drop", ok, v4, v6, tcp, udp, icmp, icmpv6, "on",
             source_addr_v6, destination_addr_v6,
             source_addr_v4, destination_addr_v4,
             ifindex, "order",
@@ -55,7 +56,29 @@ pub async fn fetch_rules() -> anyhow::Result<Vec<RuleWithName>> {
             destination_port_start, destination_port_end,
             input, output,
             source_mask_v4, destination_mask_v4,
-            source_mask_v6, destination_mask_v6
+            source_mask_v6, destination_mask_v6,
+            icmp_type, icmp_code,
+            established,
+            source_set,
+            destination_set,
+            "limit",
+            limit_rate,
+            limit_burst,
+            limit_bytes,
+            limit_per_source,
+            tcp_flags,
+            tcp_flags_mask,
+            reject,
+            monitor,
+            vlan_id,
+            ext_hdrs,
+            ext_hdrs_mask,
+            fragments,
+            ip_proto,
+            sctp,
+            dccp,
+            udplite,
+            tunnel
         FROM rules
         "#,
     )
@@ -81,6 +104,8 @@ pub async fn fetch_rules() -> anyhow::Result<Vec<RuleWithName>> {
             v6: row.get("v6"),
             tcp: row.get("tcp"),
             udp: row.get("udp"),
+            icmp: row.get("icmp"),
+            icmpv6: row.get("icmpv6"),
             on: row.get("on"),
 
             src_ip_high: (src_ip >> 64) as u64,
@@ -105,6 +130,30 @@ pub async fn fetch_rules() -> anyhow::Result<Vec<RuleWithName>> {
             destination_mask_v4: row.get("destination_mask_v4"),
             source_mask_v6: row.get("source_mask_v6"),
             destination_mask_v6: row.get("destination_mask_v6"),
+
+            icmp_type: row.get("icmp_type"),
+            icmp_code: row.get("icmp_code"),
+            established: row.get("established"),
+            source_set: row.get("source_set"),
+            destination_set: row.get("destination_set"),
+            limit: row.get("limit"),
+            limit_rate: row.get("limit_rate"),
+            limit_burst: row.get("limit_burst"),
+            limit_bytes: row.get("limit_bytes"),
+            limit_per_source: row.get("limit_per_source"),
+            tcp_flags: row.get("tcp_flags"),
+            tcp_flags_mask: row.get("tcp_flags_mask"),
+            reject: row.get("reject"),
+            monitor: row.get("monitor"),
+            vlan_id: row.get("vlan_id"),
+            ext_hdrs: row.get("ext_hdrs"),
+            ext_hdrs_mask: row.get("ext_hdrs_mask"),
+            fragments: row.get("fragments"),
+            ip_proto: row.get("ip_proto"),
+            sctp: row.get("sctp"),
+            dccp: row.get("dccp"),
+            udplite: row.get("udplite"),
+            tunnel: row.get("tunnel"),
             from_db: true,
         });
     }
@@ -123,7 +172,7 @@ pub async fn update_rule(rule: &RuleWithName) -> bool {
         r#"
         UPDATE rules SET
             rule_name = ?,
-            "drop" = ?, ok = ?, v4 = ?, v6 = ?, tcp = ?, udp = ?, "on" = ?,
+            "drop" = ?, ok = ?, v4 = ?, v6 = ?, tcp = ?, udp = ?, icmp = ?, icmpv6 = ?, "on" = ?,
             source_addr_v6 = ?, destination_addr_v6 = ?,
             source_addr_v4 = ?, destination_addr_v4 = ?,
             ifindex = ?, "order" = ?,
@@ -131,7 +180,29 @@ pub async fn update_rule(rule: &RuleWithName) -> bool {
             destination_port_start = ?, destination_port_end = ?,
             input = ?, output = ?,
             source_mask_v4 = ?, destination_mask_v4 = ?,
-            source_mask_v6 = ?, destination_mask_v6 = ?
+            source_mask_v6 = ?, destination_mask_v6 = ?,
+            icmp_type = ?, icmp_code = ?,
+            established = ?,
+            source_set = ?,
+            destination_set = ?,
+            "limit" = ?,
+            limit_rate = ?,
+            limit_burst = ?,
+            limit_bytes = ?,
+            limit_per_source = ?,
+            tcp_flags = ?,
+            tcp_flags_mask = ?,
+            reject = ?,
+            monitor = ?,
+            vlan_id = ?,
+            ext_hdrs = ?,
+            ext_hdrs_mask = ?,
+            fragments = ?,
+            ip_proto = ?,
+            sctp = ?,
+            dccp = ?,
+            udplite = ?,
+            tunnel = ?
         WHERE id = ?
         "#,
     )
@@ -142,6 +213,8 @@ pub async fn update_rule(rule: &RuleWithName) -> bool {
     .bind(rule.v6)
     .bind(rule.tcp)
     .bind(rule.udp)
+    .bind(rule.icmp)
+    .bind(rule.icmpv6)
     .bind(rule.on)
     .bind(&src_v6_str)
     .bind(&dst_v6_str)
@@ -159,6 +232,29 @@ pub async fn update_rule(rule: &RuleWithName) -> bool {
     .bind(rule.destination_mask_v4)
     .bind(rule.source_mask_v6)
     .bind(rule.destination_mask_v6)
+    .bind(rule.icmp_type)
+    .bind(rule.icmp_code)
+    .bind(rule.established)
+    .bind(&rule.source_set)
+    .bind(&rule.destination_set)
+    .bind(rule.limit)
+    .bind(rule.limit_rate)
+    .bind(rule.limit_burst)
+    .bind(rule.limit_bytes)
+    .bind(rule.limit_per_source)
+    .bind(rule.tcp_flags)
+    .bind(rule.tcp_flags_mask)
+    .bind(rule.reject)
+    .bind(rule.monitor)
+    .bind(rule.vlan_id)
+    .bind(rule.ext_hdrs)
+    .bind(rule.ext_hdrs_mask)
+    .bind(rule.fragments)
+    .bind(rule.ip_proto)
+    .bind(rule.sctp)
+    .bind(rule.dccp)
+    .bind(rule.udplite)
+    .bind(rule.tunnel)
     .bind(rule.rule_id)
     .execute(get_db())
     .await;
@@ -192,7 +288,7 @@ pub async fn insert_rule(rule: &RuleWithName) -> i64 {
         r#"
         INSERT INTO rules (
             rule_name,
-            "drop", ok, v4, v6, tcp, udp, "on",
+            "drop", ok, v4, v6, tcp, udp, icmp, icmpv6, "on",
             source_addr_v6, destination_addr_v6,
             source_addr_v4, destination_addr_v4,
             ifindex, "order",
@@ -200,8 +296,30 @@ pub async fn insert_rule(rule: &RuleWithName) -> i64 {
             destination_port_start, destination_port_end,
             input, output,
             source_mask_v4, destination_mask_v4,
-            source_mask_v6, destination_mask_v6
-        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            source_mask_v6, destination_mask_v6,
+            icmp_type, icmp_code,
+            established,
+            source_set,
+            destination_set,
+            "limit",
+            limit_rate,
+            limit_burst,
+            limit_bytes,
+            limit_per_source,
+            tcp_flags,
+            tcp_flags_mask,
+            reject,
+            monitor,
+            vlan_id,
+            ext_hdrs,
+            ext_hdrs_mask,
+            fragments,
+            ip_proto,
+            sctp,
+            dccp,
+            udplite,
+            tunnel
+        ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
         RETURNING id
         "#,
     )
@@ -212,6 +330,8 @@ pub async fn insert_rule(rule: &RuleWithName) -> i64 {
     .bind(rule.v6)
     .bind(rule.tcp)
     .bind(rule.udp)
+    .bind(rule.icmp)
+    .bind(rule.icmpv6)
     .bind(rule.on)
     .bind(&src_v6_str)
     .bind(&dst_v6_str)
@@ -229,6 +349,29 @@ pub async fn insert_rule(rule: &RuleWithName) -> i64 {
     .bind(rule.destination_mask_v4)
     .bind(rule.source_mask_v6)
     .bind(rule.destination_mask_v6)
+    .bind(rule.icmp_type)
+    .bind(rule.icmp_code)
+    .bind(rule.established)
+    .bind(&rule.source_set)
+    .bind(&rule.destination_set)
+    .bind(rule.limit)
+    .bind(rule.limit_rate)
+    .bind(rule.limit_burst)
+    .bind(rule.limit_bytes)
+    .bind(rule.limit_per_source)
+    .bind(rule.tcp_flags)
+    .bind(rule.tcp_flags_mask)
+    .bind(rule.reject)
+    .bind(rule.monitor)
+    .bind(rule.vlan_id)
+    .bind(rule.ext_hdrs)
+    .bind(rule.ext_hdrs_mask)
+    .bind(rule.fragments)
+    .bind(rule.ip_proto)
+    .bind(rule.sctp)
+    .bind(rule.dccp)
+    .bind(rule.udplite)
+    .bind(rule.tunnel)
     .execute(get_db())
     .await;
 
@@ -248,6 +391,77 @@ pub async fn insert_rule(rule: &RuleWithName) -> i64 {
     }
 }
 
+pub async fn fetch_sets() -> anyhow::Result<Vec<IpSet>> {
+    let rows = sqlx::query(
+        r#"
+        SELECT set_name, entries
+        FROM ip_sets
+        "#,
+    )
+    .fetch_all(get_db())
+    .await?;
+
+    let mut sets = Vec::with_capacity(rows.len());
+    for row in rows {
+        let name: String = row.get("set_name");
+        let entries: String = row.get("entries");
+        let mut set = IpSet::from_list(&name, &entries);
+        set.from_db = true;
+        sets.push(set);
+    }
+    Ok(sets)
+}
+
+pub async fn upsert_set(set: &IpSet) -> bool {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO ip_sets (set_name, entries) VALUES (?, ?)
+        ON CONFLICT(set_name) DO UPDATE SET entries = excluded.entries
+        "#,
+    )
+    .bind(&set.name)
+    .bind(set.entries.join("\n"))
+    .execute(get_db())
+    .await;
+
+    match result {
+        Ok(_) => {
+            info!(
+                "DB upsert_set success, name: {}, entries: {}",
+                set.name,
+                set.entries.len()
+            );
+            true
+        }
+        Err(e) => {
+            warn!("DB err in upsert_set: {:?}, name: {}", e, set.name);
+            false
+        }
+    }
+}
+
+pub async fn delete_set(name: &str) -> bool {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM ip_sets WHERE set_name = ?
+        "#,
+    )
+    .bind(name)
+    .execute(get_db())
+    .await;
+
+    match result {
+        Ok(_) => {
+            info!("DB delete_set success, name: {}", name);
+            true
+        }
+        Err(e) => {
+            warn!("DB err in delete_set: {:?}, name: {}", e, name);
+            false
+        }
+    }
+}
+
 fn parse_ipv6(s: &str) -> anyhow::Result<u128> {
     if let Ok(ip) = Ipv6Addr::from_str(s) {
         return Ok(u128::from(ip));
@@ -5,6 +5,8 @@ use elasticsearch::{
     indices::{IndicesCreateParts, IndicesExistsParts},
 };
 use log::{error, info};
+use rbpf_common::rules::rules::tcp_flags_to_string;
+use rbpf_common::tunnel::{TUNNEL_NONE, tunnel_to_str};
 use serde_json::json;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
@@ -44,6 +46,7 @@ impl ElasticLogs {
                     "v6": { "type": "boolean" },
                     "tcp": { "type": "boolean" },
                     "udp": { "type": "boolean" },
+                    "icmp": { "type": "boolean" },
 
                     "src_ip_high": { "type": "unsigned_long" },
                     "src_ip_low": { "type": "unsigned_long" },
@@ -58,6 +61,16 @@ impl ElasticLogs {
 
                     "source_port": { "type": "integer" },
                     "destination_port": { "type": "integer" },
+                    "vlan_id": { "type": "integer" },
+                    "tunnel": { "type": "keyword" },
+                    "inner_protocol": { "type": "short" },
+                    "inner_source_addr": { "type": "ip" },
+                    "inner_destination_addr": { "type": "ip" },
+                    "inner_source_port": { "type": "integer" },
+                    "inner_destination_port": { "type": "integer" },
+                    "icmp_type": { "type": "short" },
+                    "icmp_code": { "type": "short" },
+                    "tcp_flags": { "type": "keyword" },
 
                     "level": { "type": "byte" },
                     "timestamp": { "type": "date", "format": "epoch_second" },
@@ -98,6 +111,9 @@ impl ElasticLogs {
         let src_v6_addr = ((log.msg.src_ip_high as u128) << 64) | (log.msg.src_ip_low as u128);
         let dst_v6_addr = ((log.msg.dst_ip_high as u128) << 64) | (log.msg.dst_ip_low as u128);
 
+        let inner = &log.msg.inner;
+        let tunneled = inner.kind != TUNNEL_NONE;
+
         let doc = json!({
             "message": message_str,
             "input": log.msg.input,
@@ -106,6 +122,7 @@ impl ElasticLogs {
             "v6": !log.msg.v4,
             "tcp": log.msg.tcp,
             "udp": log.msg.udp,
+            "icmp": log.msg.icmp,
 
             "src_ip_high": log.msg.src_ip_high,
             "src_ip_low": log.msg.src_ip_low,
@@ -120,6 +137,16 @@ impl ElasticLogs {
 
             "source_port": log.msg.source_port,
             "destination_port": log.msg.destination_port,
+            "vlan_id": log.msg.vlan_id,
+            "tunnel": tunnel_to_str(inner.kind),
+            "inner_protocol": inner.proto,
+            "inner_source_addr": tunneled.then(|| inner.source_addr().to_string()),
+            "inner_destination_addr": tunneled.then(|| inner.destination_addr().to_string()),
+            "inner_source_port": inner.source_port,
+            "inner_destination_port": inner.destination_port,
+            "icmp_type": log.msg.icmp_type,
+            "icmp_code": log.msg.icmp_code,
+            "tcp_flags": tcp_flags_to_string(log.msg.tcp_flags),
 
             "level": log.msg.level,
             "timestamp": log.unix_time_stamp(),
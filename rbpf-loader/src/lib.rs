@@ -1,7 +1,9 @@
+pub mod candidates;
 pub mod control;
 pub mod database;
 pub mod elasticsearch;
-mod ipproto;
 pub mod logs;
+pub mod policy;
 pub mod rules;
+pub mod sets;
 pub mod settings;
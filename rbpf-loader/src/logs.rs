@@ -1,6 +1,5 @@
 use crate::control::change_socket_owner_mode;
 use crate::elasticsearch::ElasticLogs;
-use crate::ipproto;
 use crate::rules::get_rule_name;
 use crate::settings::Settings;
 use aya::maps::{MapData, RingBuf};
@@ -8,10 +7,13 @@ use core::str::from_utf8;
 use libc::if_indextoname;
 use libc::{CLOCK_MONOTONIC, clock_gettime, timespec};
 use log::{debug, error, info, warn};
+use rbpf_common::ipproto;
 use rbpf_common::logs::logs::{
     ActionType, LogMessageSerialized, ProtocolType, ProtocolVersionType, TrafficType,
 };
-use rbpf_common::logs::{DEBUG, ERROR, INFO, LogMessage, WARN};
+use rbpf_common::logs::{DEBUG, ERROR, INFO, LogMessage, MONITOR, WARN};
+use rbpf_common::rules::rules::tcp_flags_to_string;
+use rbpf_common::tunnel::{TUNNEL_NONE, tunnel_to_str};
 use std::ffi::CStr;
 use std::mem::MaybeUninit;
 use std::net::{Ipv4Addr, Ipv6Addr};
@@ -54,8 +56,14 @@ impl WLogMessage {
         let rule_action = if self.msg.rule_id != 0 {
             match get_rule_name(self.msg.rule_id).await {
                 Some(rule) => {
-                    if rule.drop {
+                    if rule.monitor {
+                        ActionType::Monitor
+                    } else if rule.reject {
+                        ActionType::Reject
+                    } else if rule.drop {
                         ActionType::Drop
+                    } else if rule.limit {
+                        ActionType::Limit
                     } else {
                         ActionType::Ok
                     }
@@ -74,8 +82,20 @@ impl WLogMessage {
             },
             protocol_type: if self.msg.tcp {
                 ProtocolType::TCP
-            } else {
+            } else if self.msg.icmp && self.msg.v4 {
+                ProtocolType::ICMP
+            } else if self.msg.icmp {
+                ProtocolType::ICMPv6
+            } else if self.msg.sctp {
+                ProtocolType::SCTP
+            } else if self.msg.dccp {
+                ProtocolType::DCCP
+            } else if self.msg.udplite {
+                ProtocolType::UDPLite
+            } else if self.msg.udp {
                 ProtocolType::UDP
+            } else {
+                ProtocolType::Other
             },
             protocol_version_type: if self.msg.v4 {
                 ProtocolVersionType::V4
@@ -93,8 +113,17 @@ impl WLogMessage {
             if_name: self.iface(),
             source_port: self.msg.source_port,
             destination_port: self.msg.destination_port,
+            vlan_id: self.msg.vlan_id,
+            icmp_type: self.msg.icmp_type,
+            icmp_code: self.msg.icmp_code,
+            tcp_flags: self.msg.tcp_flags,
             rule_name,
             timestamp: self.unix_time_stamp(),
+            inner: if self.msg.inner.kind != TUNNEL_NONE {
+                Some(self.msg.inner.to_serialized())
+            } else {
+                None
+            },
         }
     }
     pub fn iface(&self) -> String {
@@ -137,6 +166,7 @@ impl WLogMessage {
             self.dest_v4().to_string()
         };
 
+        let other_proto = format!(" {}", ipproto::from_u8_to_str(&self.msg.unhandled_protocol));
         let proto = format!(
             "{}",
             if self.msg.udp && self.msg.tcp {
@@ -145,16 +175,43 @@ impl WLogMessage {
                 " UDP"
             } else if self.msg.tcp {
                 " TCP"
+            } else if self.msg.icmp && self.msg.v4 {
+                " ICMP"
+            } else if self.msg.icmp {
+                " ICMPv6"
+            } else if self.msg.sctp {
+                " SCTP"
+            } else if self.msg.dccp {
+                " DCCP"
+            } else if self.msg.udplite {
+                " UDPLite"
+            } else if self.msg.level != ERROR && self.msg.unhandled_protocol != 255 {
+                other_proto.as_str()
             } else {
                 ""
             }
         );
 
+        let icmp = if self.msg.icmp {
+            format!(" type {} code {}", self.msg.icmp_type, self.msg.icmp_code)
+        } else if self.msg.tcp && self.msg.tcp_flags != 0 {
+            format!(" [{}]", tcp_flags_to_string(self.msg.tcp_flags))
+        } else {
+            String::new()
+        };
+
+        let iface = if self.msg.vlan_id != 0 {
+            format!("{} vlan {}", self.iface(), self.msg.vlan_id)
+        } else {
+            self.iface()
+        };
+
         let info = if self.msg.input {
             format!(
-                "INPUT: ({}{}) {} -> {}",
-                self.iface(),
+                "INPUT: ({}{}{}) {} -> {}",
+                iface,
                 proto,
+                icmp,
                 if self.msg.source_port != 0 {
                     format!("{}:{}", s_ip, self.msg.source_port)
                 } else {
@@ -168,9 +225,10 @@ impl WLogMessage {
             )
         } else {
             format!(
-                "OUTPUT: ({}{}) {} -> {}",
-                self.iface(),
+                "OUTPUT: ({}{}{}) {} -> {}",
+                iface,
                 proto,
+                icmp,
                 if self.msg.source_port != 0 {
                     format!("{}:{}", s_ip, self.msg.source_port)
                 } else {
@@ -184,6 +242,20 @@ impl WLogMessage {
             )
         };
 
+        let info = if self.msg.inner.kind != TUNNEL_NONE {
+            let inner = &self.msg.inner;
+            format!(
+                "{} via {} [{} {} -> {}]",
+                info,
+                tunnel_to_str(inner.kind),
+                ipproto::from_u8_to_str(&inner.proto),
+                with_port(inner.source_addr().to_string(), inner.source_port),
+                with_port(inner.destination_addr().to_string(), inner.destination_port),
+            )
+        } else {
+            info
+        };
+
         let msg = from_utf8(&self.msg.message).unwrap_or_else(|_| "utf-8 decode error");
         if self.msg.rule_id != 0 {
             let rule_name = get_rule_name(self.msg.rule_id).await.unwrap();
@@ -203,22 +275,35 @@ impl WLogMessage {
     }
 
     pub fn unix_time_stamp(&self) -> u64 {
-        let mut ts: timespec = unsafe { MaybeUninit::zeroed().assume_init() };
-        let res = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut ts) };
-        if res != 0 {
-            return 0;
-        }
+        ktime_to_unix(self.msg.timestamp)
+    }
+}
 
-        let now_ktime_ns = (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64);
+// bpf_ktime_get_ns() считает от загрузки системы, переводим в unix time
+fn with_port(addr: String, port: u16) -> String {
+    if port != 0 {
+        format!("{}:{}", addr, port)
+    } else {
+        addr
+    }
+}
 
-        let now = SystemTime::now();
-        let boot_time = now - Duration::from_nanos(now_ktime_ns);
-        let event_time = boot_time + Duration::from_nanos(self.msg.timestamp);
-        event_time
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs()
+pub fn ktime_to_unix(ktime_ns: u64) -> u64 {
+    let mut ts: timespec = unsafe { MaybeUninit::zeroed().assume_init() };
+    let res = unsafe { clock_gettime(CLOCK_MONOTONIC, &mut ts) };
+    if res != 0 {
+        return 0;
     }
+
+    let now_ktime_ns = (ts.tv_sec as u64) * 1_000_000_000 + (ts.tv_nsec as u64);
+
+    let now = SystemTime::now();
+    let boot_time = now - Duration::from_nanos(now_ktime_ns);
+    let event_time = boot_time + Duration::from_nanos(ktime_ns);
+    event_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 pub async fn log_listener(
@@ -251,6 +336,7 @@ pub async fn log_listener(
                             DEBUG => debug!("{}", msg_wrapper.log().await),
                             INFO => info!("{}", msg_wrapper.log().await),
                             WARN => warn!("{}", msg_wrapper.log().await),
+                            MONITOR => info!("{}", msg_wrapper.log().await),
                             _ => error!("{}", msg_wrapper.log().await),
                         }
 
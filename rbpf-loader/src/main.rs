@@ -1,6 +1,6 @@
 use crate::logs::WLogMessage;
-use aya::Ebpf;
 use aya::maps::RingBuf;
+use aya::{Ebpf, EbpfLoader};
 use log::{debug, info};
 use rbpf_loader::control;
 use rbpf_loader::logs;
@@ -30,7 +30,8 @@ async fn main() -> anyhow::Result<()> {
 async fn init_bpf() -> anyhow::Result<()> {
     info!("Initializing BPF program...");
 
-    let mut ebpf = get_rbpf().await?;
+    let map_sizes = settings::read_map_sizes().await?;
+    let mut ebpf = get_rbpf(&map_sizes).await?;
 
     let settings = Arc::new(settings::read_settings(&mut ebpf).await?);
     let logs_ring_buf = RingBuf::try_from(ebpf.take_map(logs::LOGS_RING_BUF).unwrap())?;
@@ -54,11 +55,16 @@ async fn init_bpf() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn get_rbpf() -> anyhow::Result<Ebpf> {
+async fn get_rbpf(map_sizes: &[(&'static str, u32)]) -> anyhow::Result<Ebpf> {
+    let mut loader = EbpfLoader::new();
+    for (name, size) in map_sizes {
+        loader.set_max_entries(name, *size);
+    }
+
     #[cfg(feature = "embed-ebpf")]
     {
         let bytes = aya::include_bytes_aligned!(concat!(env!("OUT_DIR"), "/rbpf"));
-        let ebpf = Ebpf::load(&bytes)?;
+        let ebpf = loader.load(&bytes)?;
         Ok(ebpf)
     }
 
@@ -72,7 +78,7 @@ async fn get_rbpf() -> anyhow::Result<Ebpf> {
             path = PathBuf::from("./rbpf.o");
         }
         let data = fs::read(path).await?;
-        let mut ebpf = Ebpf::load(&data)?;
+        let ebpf = loader.load(&data)?;
         Ok(ebpf)
     }
 }
@@ -0,0 +1,91 @@
+use aya::Ebpf;
+use aya::maps::{Array, HashMap};
+use libc::{ARPHRD_IPGRE, ARPHRD_NONE, ARPHRD_PPP, ARPHRD_SIT, ARPHRD_TUNNEL, ARPHRD_TUNNEL6};
+use log::{info, warn};
+use rbpf_common::config::{GlobalConfig, IfaceConfig, LINK_ETHERNET, LINK_RAW_IP};
+use rbpf_common::rules::rules::get_ifindex_by_name;
+use std::fs::read_to_string;
+use yaml_rust2::Yaml;
+
+const IFACE_CONFIG: &str = "IFACE_CONFIG";
+const GLOBAL_CONFIG: &str = "GLOBAL_CONFIG";
+
+// ARPHRD_RAWIP, в libc его нет
+const ARPHRD_RAWIP: u16 = 519;
+
+// Интерфейсы без Ethernet заголовка: tun и WireGuard (NONE), PPP, IP туннели
+const RAW_IP_LINKS: [u16; 7] = [
+    ARPHRD_NONE,
+    ARPHRD_PPP,
+    ARPHRD_RAWIP,
+    ARPHRD_TUNNEL,
+    ARPHRD_TUNNEL6,
+    ARPHRD_SIT,
+    ARPHRD_IPGRE,
+];
+
+pub fn load_policy(policy: &Yaml, ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let mut iface_config: HashMap<_, u32, IfaceConfig> =
+        HashMap::try_from(ebpf.map_mut(IFACE_CONFIG).unwrap())?;
+
+    match policy.as_hash() {
+        Some(ifaces) => {
+            for (iface, yaml) in ifaces {
+                let iface = iface.as_str().unwrap();
+                let ifindex = get_ifindex_by_name(iface);
+                if ifindex == 0 && iface != "*" {
+                    warn!("Unknown iface {} in policy, skip.", iface);
+                    continue;
+                }
+                let config = IfaceConfig::from_yaml(yaml).map_err(anyhow::Error::msg)?;
+                iface_config.insert(ifindex, config, 0)?;
+                info!("Loading policy for {}: {:?}", iface, config);
+            }
+        }
+        None => info!("No policy found, using defaults"),
+    }
+    Ok(())
+}
+
+// Блок `tunables`, программа подхватывает его сразу, без перезагрузки
+pub fn load_tunables(tunables: &Yaml, ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let config = GlobalConfig::from_yaml(tunables).map_err(anyhow::Error::msg)?;
+    let mut global_config: Array<_, GlobalConfig> =
+        Array::try_from(ebpf.map_mut(GLOBAL_CONFIG).unwrap())?;
+    global_config.set(0, config, 0)?;
+    info!("Loading tunables: {:?}", config);
+    Ok(())
+}
+
+// ARPHRD_* из /sys/class/net/<iface>/type
+fn link_type(iface: &str) -> u8 {
+    let path = format!("/sys/class/net/{}/type", iface);
+    match read_to_string(&path).map(|value| value.trim().parse::<u16>()) {
+        Ok(Ok(arphrd)) if RAW_IP_LINKS.contains(&arphrd) => LINK_RAW_IP,
+        Ok(Ok(_)) => LINK_ETHERNET,
+        _ => {
+            warn!("Failed to read link type of {}, assume ethernet.", iface);
+            LINK_ETHERNET
+        }
+    }
+}
+
+/// Передаёт в eBPF тип канального уровня интерфейса, политика интерфейса сохраняется.
+pub fn load_link_type(iface: &str, ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let ifindex = get_ifindex_by_name(iface);
+    if ifindex == 0 {
+        return Ok(());
+    }
+    let mut iface_config: HashMap<_, u32, IfaceConfig> =
+        HashMap::try_from(ebpf.map_mut(IFACE_CONFIG).unwrap())?;
+
+    let mut config = iface_config
+        .get(&ifindex, 0)
+        .unwrap_or(IfaceConfig::empty());
+    config.link_type = link_type(iface);
+    iface_config.insert(ifindex, config, 0)?;
+    if config.link_type == LINK_RAW_IP {
+        info!("Iface {} has no ethernet header, parse as raw IP", iface);
+    }
+    Ok(())
+}
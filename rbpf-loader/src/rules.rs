@@ -1,28 +1,77 @@
+use crate::candidates;
 use crate::database;
+use crate::logs::ktime_to_unix;
+use crate::sets;
+use anyhow::bail;
 use aya::Ebpf;
 use aya::Pod;
-use aya::maps::{HashMap, MapData};
+use aya::maps::{Array, HashMap, MapData, PerCpuHashMap};
 use log::{info, warn};
-use rbpf_common::{rules::Rule, rules::rules::RuleWithName};
+use rbpf_common::rules::rules::{RuleStatsSerialized, RuleWithName};
+use rbpf_common::rules::{
+    MAX_CANDIDATES, MAX_RULE_CLASSES, MAX_RULES, Rule, RuleCandidates, RuleClass, RuleStats,
+};
 use std::collections::HashMap as RustHashMap;
 use std::fs::read_dir;
 use std::sync::Arc;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, Mutex, OnceLock};
 use std::vec::Vec;
 use tokio::fs::read_to_string;
 use tokio::sync::RwLock;
 use yaml_rust2::YamlLoader;
 
-const RULES: &str = "RULES";
+// Два поколения map правил, активное указано в RULES_GENERATION
+const RULES: [&str; 2] = ["RULES_0", "RULES_1"];
+const RULES_CLASSES: [&str; 2] = ["RULES_CLASSES_0", "RULES_CLASSES_1"];
+const RULES_CANDIDATES: [&str; 2] = ["RULES_CANDIDATES_0", "RULES_CANDIDATES_1"];
+const RULES_GENERATION: &str = "RULES_GENERATION";
+const RULES_STATS: &str = "RULES_STATS";
+
+// Сколько правил и кандидатов помещается в map, задаётся блоком `maps` в main.yaml
+#[derive(Debug, Clone, Copy)]
+pub struct Capacity {
+    pub rules: u32,
+    pub classes: u32,
+    pub candidates: u32,
+}
+
+static CAPACITY: OnceLock<Capacity> = OnceLock::new();
 
 static STORE: LazyLock<Arc<RwLock<RustHashMap<u32, RuleWithName>>>> =
     LazyLock::new(|| Arc::new(RwLock::new(RustHashMap::new())));
 
+pub fn set_capacity(sizes: &[(&str, u32)]) {
+    let size = |name: &str, default: u32| {
+        sizes
+            .iter()
+            .find(|(map, _)| *map == name)
+            .map_or(default, |(_, size)| *size)
+    };
+    let _ = CAPACITY.set(Capacity {
+        rules: size(RULES[0], MAX_RULES),
+        classes: size(RULES_CLASSES[0], MAX_RULE_CLASSES),
+        candidates: size(RULES_CANDIDATES[0], MAX_CANDIDATES),
+    });
+}
+
+pub fn capacity() -> Capacity {
+    *CAPACITY.get_or_init(|| Capacity {
+        rules: MAX_RULES,
+        classes: MAX_RULE_CLASSES,
+        candidates: MAX_CANDIDATES,
+    })
+}
+
 pub async fn set_rule(value: RuleWithName) {
     let mut store = STORE.write().await;
     store.insert(value.rule_id, value);
 }
 
+pub async fn set_rules(rules: RustHashMap<u32, RuleWithName>) {
+    let mut store = STORE.write().await;
+    *store = rules;
+}
+
 pub async fn change_rule(value: RuleWithName) {
     let mut store = STORE.write().await;
     if value.from_db {
@@ -32,6 +81,19 @@ pub async fn change_rule(value: RuleWithName) {
     store.insert(value.rule_id, value);
 }
 
+// Изменилось только поле on
+pub async fn is_switch(value: &RuleWithName) -> bool {
+    let store = STORE.read().await;
+    match store.get(&value.rule_id) {
+        Some(rule) => {
+            let mut switched = rule.clone();
+            switched.on = value.on;
+            switched == *value
+        }
+        None => false,
+    }
+}
+
 pub async fn get_rule_name(key: u32) -> Option<RuleWithName> {
     let store = STORE.read().await;
     store.get(&key).cloned()
@@ -48,7 +110,15 @@ pub async fn get_rules_len() -> u32 {
 }
 
 pub async fn load_rules_from_dir(path: &str) -> anyhow::Result<()> {
+    for rule in read_rules_from_dir(path).await? {
+        set_rule(rule).await;
+    }
+    Ok(())
+}
+
+pub async fn read_rules_from_dir(path: &str) -> anyhow::Result<Vec<RuleWithName>> {
     info!("Loading rules from dir {}...", path);
+    let mut rules = Vec::new();
     let paths = read_dir(path);
     match paths {
         Ok(paths) => {
@@ -65,14 +135,13 @@ pub async fn load_rules_from_dir(path: &str) -> anyhow::Result<()> {
                 }
                 let srule = read_to_string(path).await?;
                 let yrule = &YamlLoader::load_from_str(&srule)?[0];
-                let rule = RuleWithName::from_yaml(yrule);
-                set_rule(rule.clone()).await;
+                rules.push(RuleWithName::from_yaml(yrule));
             }
-            Ok(())
+            Ok(rules)
         }
         Err(_) => {
             warn!("No rules found in dir {}, skip loading yaml.", path);
-            Ok(())
+            Ok(rules)
         }
     }
 }
@@ -87,32 +156,269 @@ pub async fn load_rules_from_db() -> anyhow::Result<()> {
 }
 
 pub async fn reload_rules(ebpf: &mut Ebpf) -> anyhow::Result<()> {
-    let mut rules_input: HashMap<_, u32, Rule> = HashMap::try_from(ebpf.map_mut(RULES).unwrap())?;
+    make_bpf_maps(ebpf).await
+}
 
-    clear_hashmap(&mut rules_input);
-    make_bpf_maps(ebpf).await?;
+pub async fn make_bpf_maps(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let prepared = prepare_rules(get_rules().await.into_values().collect()).await?;
+    let mut rules = Vec::new();
+    for (rule, common_rule) in prepared {
+        info!(
+            "Loading rule {}, original order: {}, set as {}",
+            rule.name,
+            rule.order,
+            rules.len()
+        );
+        rules.push(common_rule);
+    }
+    write_bpf_maps(ebpf, &rules, false)
+}
 
-    Ok(())
+/// Проверяет, что правила поместятся в map, ничего не меняя ни в хранилище, ни в ядре.
+pub async fn check_capacity(rules: Vec<RuleWithName>) -> anyhow::Result<()> {
+    prepare_rules(rules).await.map(|_| ())
 }
 
-pub async fn make_bpf_maps(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+// Правила в порядке загрузки в RULES, правила с неизвестными сетами пропускаются.
+// Только проверка, в лог о загрузке пишет make_bpf_maps
+async fn prepare_rules(mut rules: Vec<RuleWithName>) -> anyhow::Result<Vec<(RuleWithName, Rule)>> {
+    rules.sort_by_key(|rule| rule.order);
+
+    let mut common_rules = Vec::new();
+    for rule in rules {
+        let mut common_rule = rule.to_common_rule();
+        // Правило с неизвестным сетом не грузим, иначе оно сработает на любой адрес
+        match resolve_sets(&rule, &mut common_rule).await {
+            Ok(_) => {}
+            Err(set) => {
+                warn!("Unknown ip set {} in rule {}, skip.", set, rule.name);
+                continue;
+            }
+        }
+        common_rules.push((rule, common_rule));
+    }
+
+    let capacity = capacity();
+    if common_rules.len() > capacity.rules as usize {
+        bail!(
+            "{} rules do not fit in RULES map of {}, increase maps.rules in main.yaml",
+            common_rules.len(),
+            capacity.rules
+        );
+    }
+    // Не поместившиеся списки кандидатов заменяются одним списком, он должен поместиться всегда
+    let rules: Vec<Rule> = common_rules.iter().map(|(_, rule)| *rule).collect();
+    if !candidates::build(&rules, true).fits(&capacity) {
+        bail!(
+            "{} rules do not fit in RULES_CANDIDATES map of {}, increase maps.rule_candidates in main.yaml",
+            common_rules.len(),
+            capacity.candidates
+        );
+    }
+    Ok(common_rules)
+}
+
+/// Раскладывает правила по спискам кандидатов и записывает в неактивное поколение map.
+/// linear - без предварительной классификации, каждый пакет проверяется всеми правилами.
+pub fn write_bpf_maps(ebpf: &mut Ebpf, rules: &[Rule], linear: bool) -> anyhow::Result<()> {
+    let index = candidates::build_within(rules, linear, &capacity());
+    write_generation(
+        ebpf,
+        Written {
+            rules: rules.to_vec(),
+            candidates: index.candidates,
+            classes: index.classes.into_iter().collect(),
+        },
+    )
+}
+
+/// Включает / выключает правило без пересортировки и пересборки списков кандидатов:
+/// выключенные правила остаются в списках, ядро пропускает их по `on`.
+/// false - правила нет в map (например, не найден его сет), нужна полная перезагрузка.
+pub fn switch_rule(ebpf: &mut Ebpf, rule_id: u32, on: bool) -> anyhow::Result<bool> {
+    let active = get_generation(ebpf)? as usize;
+    let mut next = match &WRITTEN.lock().unwrap()[active] {
+        Some(written) => written.clone(),
+        None => return Ok(false),
+    };
+    match next.rules.iter_mut().find(|rule| rule.rule_id == rule_id) {
+        Some(rule) => rule.on = on,
+        None => return Ok(false),
+    }
+    write_generation(ebpf, next)?;
+    Ok(true)
+}
+
+// Что записано в поколение map, по нему пишутся только изменившиеся записи
+#[derive(Clone)]
+struct Written {
+    rules: Vec<Rule>,
+    candidates: Vec<u32>,
+    classes: RustHashMap<RuleClass, RuleCandidates>,
+}
+
+// None - содержимое поколения неизвестно (ещё не писали или запись прервалась)
+static WRITTEN: LazyLock<Mutex<[Option<Written>; 2]>> = LazyLock::new(|| Mutex::new([None, None]));
+
+// Записи, которыми поколение prev превращается в next
+#[derive(Debug, Default, PartialEq)]
+struct GenerationDiff {
+    rules: Vec<(u32, Rule)>,
+    candidates: Vec<(u32, u32)>,
+    classes: Vec<(RuleClass, RuleCandidates)>,
+    removed_classes: Vec<RuleClass>,
+    // Содержимое RULES_CLASSES неизвестно, его надо очистить целиком
+    clear_classes: bool,
+}
+
+// Хвост массивов за концом next затирается пустыми записями, чтобы в map не оставалось
+// правил, которых уже нет. После прерванной записи (prev = None) хвост неизвестен, но на него
+// не ссылается ни один список кандидатов.
+fn diff_generation(prev: Option<&Written>, next: &Written) -> GenerationDiff {
+    let mut diff = GenerationDiff {
+        clear_classes: prev.is_none(),
+        ..Default::default()
+    };
+    let (prev_rules, prev_candidates) = match prev {
+        Some(prev) => (prev.rules.as_slice(), prev.candidates.as_slice()),
+        None => (&[][..], &[][..]),
+    };
+
+    for (index, rule) in next.rules.iter().enumerate() {
+        if prev_rules.get(index) != Some(rule) {
+            diff.rules.push((index as u32, *rule));
+        }
+    }
+    let empty = RuleWithName::from_empty().to_common_rule();
+    for index in next.rules.len()..prev_rules.len() {
+        diff.rules.push((index as u32, empty));
+    }
+
+    for (position, rule_index) in next.candidates.iter().enumerate() {
+        if prev_candidates.get(position) != Some(rule_index) {
+            diff.candidates.push((position as u32, *rule_index));
+        }
+    }
+    for position in next.candidates.len()..prev_candidates.len() {
+        diff.candidates.push((position as u32, 0));
+    }
+
+    if let Some(prev) = prev {
+        diff.removed_classes = prev
+            .classes
+            .keys()
+            .filter(|class| !next.classes.contains_key(class))
+            .copied()
+            .collect();
+    }
+    diff.classes = next
+        .classes
+        .iter()
+        .filter(|(class, range)| prev.and_then(|prev| prev.classes.get(class)) != Some(range))
+        .map(|(class, range)| (*class, *range))
+        .collect();
+    diff
+}
+
+// Пишет неактивное поколение и переключает на него. Если запись не удалась,
+// пакеты продолжают проверяться прежним набором правил.
+fn write_generation(ebpf: &mut Ebpf, next: Written) -> anyhow::Result<()> {
+    let mut written = WRITTEN.lock().unwrap();
+    let generation = 1 - get_generation(ebpf)? as usize;
+    let prev = written[generation].take();
+    let diff = diff_generation(prev.as_ref(), &next);
+
     {
-        let mut rules_map: HashMap<_, u32, Rule> = HashMap::try_from(ebpf.map_mut(RULES).unwrap())?;
+        let mut rules_map: Array<_, Rule> =
+            Array::try_from(ebpf.map_mut(RULES[generation]).unwrap())?;
+        for (index, rule) in diff.rules.iter() {
+            rules_map.set(*index, rule, 0)?;
+        }
+    }
 
-        let mut rules: Vec<_> = get_rules().await.into_values().collect();
-        rules.sort_by_key(|rule| rule.order);
+    {
+        let mut candidates_map: Array<_, u32> =
+            Array::try_from(ebpf.map_mut(RULES_CANDIDATES[generation]).unwrap())?;
+        for (position, rule_index) in diff.candidates.iter() {
+            candidates_map.set(*position, rule_index, 0)?;
+        }
+    }
 
-        for (new_order, rule) in rules.into_iter().enumerate() {
-            rules_map.insert(new_order as u32, rule.to_common_rule(), 0)?;
-            info!(
-                "Loading rule {}, original order: {}, set as {}",
-                rule.name, rule.order, new_order
-            );
+    {
+        let mut classes_map: HashMap<_, RuleClass, RuleCandidates> =
+            HashMap::try_from(ebpf.map_mut(RULES_CLASSES[generation]).unwrap())?;
+        if diff.clear_classes {
+            clear_hashmap(&mut classes_map);
+        }
+        for class in diff.removed_classes.iter() {
+            classes_map.remove(class)?;
+        }
+        for (class, range) in diff.classes.iter() {
+            classes_map.insert(class, range, 0)?;
         }
     }
+
+    // Пакеты, начавшие проверку до переключения, дочитывают старое поколение,
+    // его перезапишет только следующее изменение правил
+    let mut generation_map: Array<_, u32> =
+        Array::try_from(ebpf.map_mut(RULES_GENERATION).unwrap())?;
+    generation_map.set(0, generation as u32, 0)?;
+    info!(
+        "Rule generation {}, changed rules: {}, candidates: {}, classes: {}",
+        generation,
+        diff.rules.len(),
+        diff.candidates.len(),
+        diff.classes.len() + diff.removed_classes.len()
+    );
+    written[generation] = Some(next);
     Ok(())
 }
 
+fn get_generation(ebpf: &Ebpf) -> anyhow::Result<u32> {
+    let generation_map: Array<_, u32> = Array::try_from(ebpf.map(RULES_GENERATION).unwrap())?;
+    Ok(generation_map.get(&0, 0)?)
+}
+
+async fn resolve_sets(rule: &RuleWithName, common_rule: &mut Rule) -> Result<(), String> {
+    if !rule.source_set.is_empty() {
+        common_rule.source_set_id = sets::get_set_id(&rule.source_set)
+            .await
+            .ok_or(rule.source_set.clone())?;
+    }
+    if !rule.destination_set.is_empty() {
+        common_rule.destination_set_id = sets::get_set_id(&rule.destination_set)
+            .await
+            .ok_or(rule.destination_set.clone())?;
+    }
+    Ok(())
+}
+
+pub fn get_rule_stats(ebpf: &Ebpf, rule_id: u32) -> anyhow::Result<RuleStatsSerialized> {
+    let stats_map: PerCpuHashMap<_, u32, RuleStats> =
+        PerCpuHashMap::try_from(ebpf.map(RULES_STATS).unwrap())?;
+
+    let mut stats = RuleStatsSerialized {
+        rule_id,
+        packets: 0,
+        bytes: 0,
+        last_hit_ns: 0,
+        last_hit: 0,
+    };
+
+    // Правило ещё ни разу не срабатывало - в map его нет
+    if let Ok(per_cpu) = stats_map.get(&rule_id, 0) {
+        for cpu_stats in per_cpu.iter() {
+            stats.packets += cpu_stats.packets;
+            stats.bytes += cpu_stats.bytes;
+            stats.last_hit_ns = stats.last_hit_ns.max(cpu_stats.last_hit_ns);
+        }
+    }
+    if stats.last_hit_ns != 0 {
+        stats.last_hit = ktime_to_unix(stats.last_hit_ns);
+    }
+    Ok(stats)
+}
+
 pub fn clear_hashmap<K, V>(map: &mut HashMap<&mut MapData, K, V>)
 where
     K: Pod + Eq + Copy,
@@ -128,3 +434,122 @@ where
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TCP_IN: RuleClass = RuleClass {
+        proto: libc::IPPROTO_TCP as u8,
+        input: true,
+        port: 0,
+    };
+    const UDP_IN: RuleClass = RuleClass {
+        proto: libc::IPPROTO_UDP as u8,
+        input: true,
+        port: 0,
+    };
+
+    fn rule(rule_id: u32) -> Rule {
+        let mut rule = RuleWithName::from_empty();
+        rule.rule_id = rule_id;
+        rule.on = true;
+        rule.drop = true;
+        rule.input = true;
+        rule.tcp = true;
+        rule.to_common_rule()
+    }
+
+    fn written(rules: Vec<Rule>, classes: &[(RuleClass, u32, u32)]) -> Written {
+        Written {
+            candidates: (0..rules.len() as u32).collect(),
+            rules,
+            classes: classes
+                .iter()
+                .map(|&(class, start, len)| (class, RuleCandidates { start, len }))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn first_write_writes_everything() {
+        let next = written(vec![rule(1), rule(2)], &[(TCP_IN, 0, 2)]);
+        let diff = diff_generation(None, &next);
+        assert_eq!(diff.rules, vec![(0, rule(1)), (1, rule(2))]);
+        assert_eq!(diff.candidates, vec![(0, 0), (1, 1)]);
+        assert_eq!(
+            diff.classes,
+            vec![(TCP_IN, RuleCandidates { start: 0, len: 2 })]
+        );
+        assert!(diff.removed_classes.is_empty());
+        assert!(diff.clear_classes);
+    }
+
+    #[test]
+    fn unchanged_generation_writes_nothing() {
+        let prev = written(vec![rule(1), rule(2)], &[(TCP_IN, 0, 2)]);
+        let diff = diff_generation(Some(&prev), &prev.clone());
+        assert_eq!(diff, GenerationDiff::default());
+    }
+
+    #[test]
+    fn changed_rule_is_written() {
+        let prev = written(vec![rule(1), rule(2), rule(3)], &[(TCP_IN, 0, 3)]);
+        let mut next = prev.clone();
+        next.rules[1].on = false;
+        let diff = diff_generation(Some(&prev), &next);
+        assert_eq!(diff.rules, vec![(1, next.rules[1])]);
+        assert!(diff.candidates.is_empty());
+        assert!(diff.classes.is_empty());
+        assert!(!diff.clear_classes);
+    }
+
+    #[test]
+    fn reordered_rules_are_written() {
+        let prev = written(vec![rule(1), rule(2), rule(3)], &[(TCP_IN, 0, 3)]);
+        let next = written(vec![rule(1), rule(3), rule(2)], &[(TCP_IN, 0, 3)]);
+        let diff = diff_generation(Some(&prev), &next);
+        assert_eq!(diff.rules, vec![(1, rule(3)), (2, rule(2))]);
+        assert!(diff.candidates.is_empty());
+        assert!(diff.classes.is_empty());
+    }
+
+    #[test]
+    fn removed_rule_clears_tail() {
+        let prev = written(vec![rule(1), rule(2), rule(3)], &[(TCP_IN, 0, 3)]);
+        let next = written(vec![rule(1), rule(3)], &[(TCP_IN, 0, 2)]);
+        let diff = diff_generation(Some(&prev), &next);
+
+        let empty = RuleWithName::from_empty().to_common_rule();
+        assert!(!empty.on);
+        assert_eq!(diff.rules, vec![(1, rule(3)), (2, empty)]);
+        assert_eq!(diff.candidates, vec![(2, 0)]);
+        assert_eq!(
+            diff.classes,
+            vec![(TCP_IN, RuleCandidates { start: 0, len: 2 })]
+        );
+    }
+
+    #[test]
+    fn removed_class_is_deleted() {
+        let prev = written(vec![rule(1), rule(2)], &[(TCP_IN, 0, 1), (UDP_IN, 1, 1)]);
+        let next = written(vec![rule(1), rule(2)], &[(TCP_IN, 0, 1)]);
+        let diff = diff_generation(Some(&prev), &next);
+        assert_eq!(diff.removed_classes, vec![UDP_IN]);
+        assert!(diff.classes.is_empty());
+        assert!(diff.rules.is_empty());
+    }
+
+    #[test]
+    fn added_rule_extends_arrays() {
+        let prev = written(vec![rule(1)], &[(TCP_IN, 0, 1)]);
+        let next = written(vec![rule(1), rule(2)], &[(TCP_IN, 0, 2)]);
+        let diff = diff_generation(Some(&prev), &next);
+        assert_eq!(diff.rules, vec![(1, rule(2))]);
+        assert_eq!(diff.candidates, vec![(1, 1)]);
+        assert_eq!(
+            diff.classes,
+            vec![(TCP_IN, RuleCandidates { start: 0, len: 2 })]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use crate::database;
+use aya::Ebpf;
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::maps::{MapData, MapError};
+use log::{info, warn};
+use rbpf_common::sets::sets::{IpNetwork, IpSet};
+use rbpf_common::sets::{IP_SET_ID_BITS, IpSetV4, IpSetV6};
+use std::collections::HashMap as RustHashMap;
+use std::collections::HashSet;
+use std::fs::read_dir;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use tokio::fs::read_to_string;
+use tokio::sync::RwLock;
+
+const IP_SET_V4: &str = "IP_SET_V4";
+const IP_SET_V6: &str = "IP_SET_V6";
+
+// Ключ - имя сета, правила ссылаются на сеты по имени
+static STORE: LazyLock<Arc<RwLock<RustHashMap<String, IpSet>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(RustHashMap::new())));
+
+/// Добавляет или заменяет сет, id сохраняется между перезагрузками пока жив процесс.
+pub async fn set_ip_set(mut value: IpSet) -> IpSet {
+    let mut store = STORE.write().await;
+    value.set_id = match store.get(&value.name) {
+        Some(old) => old.set_id,
+        None => store.values().map(|set| set.set_id).max().unwrap_or(0) + 1,
+    };
+    store.insert(value.name.clone(), value.clone());
+    value
+}
+
+pub async fn remove_ip_set(name: &str) -> Option<IpSet> {
+    let mut store = STORE.write().await;
+    store.remove(name)
+}
+
+pub async fn get_sets() -> RustHashMap<String, IpSet> {
+    let store = STORE.read().await;
+    store.clone()
+}
+
+pub async fn get_set_id(name: &str) -> Option<u32> {
+    let store = STORE.read().await;
+    store.get(name).map(|set| set.set_id)
+}
+
+pub async fn load_sets_from_dir(path: &str) -> anyhow::Result<()> {
+    info!("Loading ip sets from dir {}...", path);
+    let paths = read_dir(path);
+    match paths {
+        Ok(paths) => {
+            for path in paths {
+                let path = path?.path();
+                let file_name = path.file_name().unwrap().to_str().unwrap();
+                let Some(name) = file_name.strip_suffix(".list") else {
+                    continue;
+                };
+                let list = read_to_string(&path).await?;
+                let set = set_ip_set(IpSet::from_list(name, &list)).await;
+                info!("Loaded ip set {} ({} entries)", set.name, set.entries.len());
+            }
+            Ok(())
+        }
+        Err(_) => {
+            warn!("No ip sets found in dir {}, skip loading.", path);
+            Ok(())
+        }
+    }
+}
+
+pub async fn load_sets_from_db() -> anyhow::Result<()> {
+    info!("Loading ip sets from DB...");
+    let sets = database::fetch_sets().await?;
+    for set in sets {
+        set_ip_set(set).await;
+    }
+    Ok(())
+}
+
+pub async fn make_bpf_maps(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    for set in get_sets().await.into_values() {
+        load_set(ebpf, &set)?;
+    }
+    Ok(())
+}
+
+/// Заливает сет в ядро. Сначала добавляются новые сети, потом удаляются устаревшие,
+/// поэтому во время обновления сет не бывает пустым.
+pub fn load_set(ebpf: &mut Ebpf, set: &IpSet) -> anyhow::Result<()> {
+    let set_id = set.set_id.to_be();
+    let mut loaded = 0;
+    {
+        let mut set_v4: LpmTrie<_, IpSetV4, u8> =
+            LpmTrie::try_from(ebpf.map_mut(IP_SET_V4).unwrap())?;
+        let mut wanted = HashSet::new();
+        for entry in &set.entries {
+            if let Some(IpNetwork::V4(addr, mask)) = IpNetwork::parse(entry).map(IpNetwork::network)
+            {
+                let key = Key::new(IP_SET_ID_BITS + mask as u32, IpSetV4::new(set.set_id, addr));
+                wanted.insert((key.prefix_len(), key.data().addr));
+                match set_v4.insert(&key, 1, 0) {
+                    Ok(_) => loaded += 1,
+                    Err(e) => warn!("Failed to insert {} into ip set {}: {}", entry, set.name, e),
+                }
+            }
+        }
+        let stale: Vec<Key<IpSetV4>> = set_v4
+            .keys()
+            .filter_map(Result::ok)
+            .filter(|key| key.data().set_id == set_id)
+            .filter(|key| !wanted.contains(&(key.prefix_len(), key.data().addr)))
+            .collect();
+        remove_keys(&mut set_v4, stale);
+    }
+    {
+        let mut set_v6: LpmTrie<_, IpSetV6, u8> =
+            LpmTrie::try_from(ebpf.map_mut(IP_SET_V6).unwrap())?;
+        let mut wanted = HashSet::new();
+        for entry in &set.entries {
+            match IpNetwork::parse(entry).map(IpNetwork::network) {
+                Some(IpNetwork::V6(addr, mask)) => {
+                    let key =
+                        Key::new(IP_SET_ID_BITS + mask as u32, IpSetV6::new(set.set_id, addr));
+                    wanted.insert((key.prefix_len(), key.data().addr));
+                    match set_v6.insert(&key, 1, 0) {
+                        Ok(_) => loaded += 1,
+                        Err(e) => {
+                            warn!("Failed to insert {} into ip set {}: {}", entry, set.name, e)
+                        }
+                    }
+                }
+                Some(IpNetwork::V4(_, _)) => {}
+                None => warn!("Bad entry {} in ip set {}, skip.", entry, set.name),
+            }
+        }
+        let stale: Vec<Key<IpSetV6>> = set_v6
+            .keys()
+            .filter_map(Result::ok)
+            .filter(|key| key.data().set_id == set_id)
+            .filter(|key| !wanted.contains(&(key.prefix_len(), key.data().addr)))
+            .collect();
+        remove_keys(&mut set_v6, stale);
+    }
+    info!(
+        "Loading ip set {}, id: {}, entries: {}",
+        set.name, set.set_id, loaded
+    );
+    Ok(())
+}
+
+pub fn clear_set(ebpf: &mut Ebpf, set_id: u32) -> anyhow::Result<()> {
+    let set_id = set_id.to_be();
+    {
+        let mut set_v4: LpmTrie<_, IpSetV4, u8> =
+            LpmTrie::try_from(ebpf.map_mut(IP_SET_V4).unwrap())?;
+        let keys: Vec<Key<IpSetV4>> = set_v4
+            .keys()
+            .filter_map(Result::ok)
+            .filter(|key| key.data().set_id == set_id)
+            .collect();
+        remove_keys(&mut set_v4, keys);
+    }
+    {
+        let mut set_v6: LpmTrie<_, IpSetV6, u8> =
+            LpmTrie::try_from(ebpf.map_mut(IP_SET_V6).unwrap())?;
+        let keys: Vec<Key<IpSetV6>> = set_v6
+            .keys()
+            .filter_map(Result::ok)
+            .filter(|key| key.data().set_id == set_id)
+            .collect();
+        remove_keys(&mut set_v6, keys);
+    }
+    Ok(())
+}
+
+fn remove_keys<K: aya::Pod>(map: &mut LpmTrie<&mut MapData, K, u8>, keys: Vec<Key<K>>) {
+    for key in keys {
+        match map.remove(&key) {
+            Ok(_) | Err(MapError::KeyNotFound) => {}
+            Err(e) => warn!("Err {} while clearing ip set", e),
+        }
+    }
+}
@@ -1,16 +1,43 @@
 use crate::database;
+use crate::policy;
 use crate::rules;
+use crate::sets;
+use anyhow::bail;
 use aya::Ebpf;
+use aya::programs::tc::qdisc_add_clsact;
 use aya::programs::{SchedClassifier, TcAttachType, Xdp, XdpFlags};
 use clap::Parser;
-use log::{info, warn};
+use log::{debug, info, warn};
 use tokio::fs::read_to_string;
 use tokio::process::Command;
 use yaml_rust2::{Yaml, YamlLoader};
 
+// xdp_ingress_frags умеет multi-buffer XDP (jumbo frames), xdp_ingress - только линейные пакеты
+const XDP_FRAGS: &str = "xdp_ingress_frags";
+const XDP_LINEAR: &str = "xdp_ingress";
+const TC_INGRESS: &str = "tc_ingress";
+
+// Блок `maps`: ключ в main.yaml и map eBPF программы, размер которых он задаёт
+const MAP_SIZES: [(&str, &[&str]); 9] = [
+    ("rules", &["RULES_0", "RULES_1", "RULES_STATS"]),
+    ("rule_classes", &["RULES_CLASSES_0", "RULES_CLASSES_1"]),
+    (
+        "rule_candidates",
+        &["RULES_CANDIDATES_0", "RULES_CANDIDATES_1"],
+    ),
+    ("logs_ring_buf", &["LOGS_RING_BUF"]),
+    ("conntrack", &["CONNTRACK"]),
+    ("limits", &["LIMITS"]),
+    ("fragments", &["FRAGMENTS"]),
+    ("ip_set_v4", &["IP_SET_V4"]),
+    ("ip_set_v6", &["IP_SET_V6"]),
+];
+
 #[derive(Debug, Clone)]
 pub struct Settings {
+    pub cfg_path: String,
     pub rules_path: String,
+    pub sets_path: String,
 
     pub control_on: bool,
     pub control_socket_path: String,
@@ -37,6 +64,9 @@ pub struct Opt {
     #[clap(short, long, default_value = "./rules/")]
     rules: String,
 
+    #[clap(short, long, default_value = "./sets/")]
+    sets: String,
+
     #[clap(short, long, default_value = "./migrations/")]
     pub migrations: String,
 
@@ -47,6 +77,49 @@ pub struct Opt {
     pub fo: bool,
 }
 
+/// Размеры map из блока `maps`, применяются до загрузки eBPF программы.
+/// Не заданные в main.yaml map остаются с размером по умолчанию.
+pub async fn read_map_sizes() -> anyhow::Result<Vec<(&'static str, u32)>> {
+    let opt = Opt::parse();
+
+    let yaml = read_to_string(&opt.cfg).await?;
+    let settings = YamlLoader::load_from_str(&yaml)?;
+    let maps = &settings[0]["maps"];
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+    let mut sizes = Vec::new();
+    for (key, names) in MAP_SIZES {
+        let size = match maps[key].as_i64() {
+            Some(size) => u32::try_from(size)?,
+            None => continue,
+        };
+        if size == 0 {
+            bail!("maps.{} must be greater than 0", key);
+        }
+        // Размер ring buffer в байтах, степень двойки и кратен размеру страницы
+        if key == "logs_ring_buf" && (!size.is_power_of_two() || size % page_size != 0) {
+            bail!(
+                "maps.logs_ring_buf must be a power of two multiple of page size {}, got {}",
+                page_size,
+                size
+            );
+        }
+        info!("Map size {}: {}", key, size);
+        for name in names {
+            sizes.push((*name, size));
+        }
+    }
+    rules::set_capacity(&sizes);
+    Ok(sizes)
+}
+
+/// Блок `tunables` из main.yaml, для ControlAction::ReloadTunables
+pub async fn reload_tunables(settings: &Settings, ebpf: &mut Ebpf) -> anyhow::Result<()> {
+    let yaml = read_to_string(&settings.cfg_path).await?;
+    let yaml = YamlLoader::load_from_str(&yaml)?;
+    policy::load_tunables(&yaml[0]["tunables"], ebpf)
+}
+
 pub async fn read_settings(ebpf: &mut Ebpf) -> anyhow::Result<Settings> {
     let opt = Opt::parse();
 
@@ -58,9 +131,12 @@ pub async fn read_settings(ebpf: &mut Ebpf) -> anyhow::Result<Settings> {
     let elk = &settings[0]["elk"];
 
     rules::load_rules_from_dir(&opt.rules).await?;
+    sets::load_sets_from_dir(&opt.sets).await?;
 
     let settings_struct = Settings {
+        cfg_path: opt.cfg,
         rules_path: opt.rules,
+        sets_path: opt.sets,
         control_socket_path: control["control_socket_path"].as_str().unwrap().to_string(),
         control_socket_owner: control["control_socket_owner"]
             .as_str()
@@ -86,11 +162,15 @@ pub async fn read_settings(ebpf: &mut Ebpf) -> anyhow::Result<Settings> {
         database::init_db(&settings_struct.db_path).await?;
         database::migrate(&opt.migrations).await?;
         rules::load_rules_from_db().await?;
+        sets::load_sets_from_db().await?;
     } else {
         info!("Database off.")
     }
 
+    sets::make_bpf_maps(ebpf).await?;
     rules::make_bpf_maps(ebpf).await?;
+    policy::load_policy(&settings[0]["policy"], ebpf)?;
+    policy::load_tunables(&settings[0]["tunables"], ebpf)?;
     init_ifaces(settings, ebpf, opt.fi, opt.fo).await?;
 
     Ok(settings_struct)
@@ -104,6 +184,16 @@ async fn init_ifaces(
 ) -> anyhow::Result<()> {
     let interfaces = &settings[0]["interfaces"];
 
+    let inputs = interfaces["input"].as_vec().into_iter().flatten();
+    let outputs = interfaces["output"].as_vec().into_iter().flatten();
+    for iface in inputs {
+        let (iface, _) = input_iface(iface)?;
+        policy::load_link_type(iface, ebpf)?;
+    }
+    for iface in outputs {
+        policy::load_link_type(iface.as_str().unwrap(), ebpf)?;
+    }
+
     match interfaces["output"].as_vec() {
         Some(interfaces) => {
             let program_egress: &mut SchedClassifier =
@@ -126,19 +216,37 @@ async fn init_ifaces(
 
     match interfaces["input"].as_vec() {
         Some(interfaces) => {
-            let program_ingress: &mut Xdp = ebpf.program_mut("xdp_ingress").unwrap().try_into()?;
-            program_ingress.load()?;
+            // Ядра до 5.18 не знают xdp.frags, тогда остаётся только обычная программа
+            let frags = match load_xdp(ebpf, XDP_FRAGS) {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Multi-buffer XDP is not supported: {}", e);
+                    false
+                }
+            };
+            load_xdp(ebpf, XDP_LINEAR)?;
+            let mut tc = false;
 
             for iface in interfaces {
-                let iface = iface.as_str().unwrap();
+                let (iface, mode) = input_iface(iface)?;
 
                 if fi {
                     force_in(iface).await;
                 }
 
-                let res = program_ingress.attach(&iface, XdpFlags::default());
+                let res = if mode == InputMode::Tc {
+                    if !tc {
+                        let program: &mut SchedClassifier =
+                            ebpf.program_mut(TC_INGRESS).unwrap().try_into()?;
+                        program.load()?;
+                        tc = true;
+                    }
+                    attach_tc_ingress(ebpf, iface)
+                } else {
+                    attach_input(ebpf, iface, mode, frags)
+                };
                 match res {
-                    Ok(_) => info!("Append input listener to: {}", iface),
+                    Ok(attached) => info!("Append input listener to: {} ({})", iface, attached),
                     Err(e) => warn!("Failed to attach input: {}, iface: {}", e, iface),
                 }
             }
@@ -148,6 +256,107 @@ async fn init_ifaces(
     Ok(())
 }
 
+// Режим подключения входящей программы, `mode` у интерфейса в `interfaces.input`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InputMode {
+    // native, если драйвер не умеет - generic
+    Auto,
+    Native,
+    Skb,
+    Hw,
+    // tc_ingress вместо XDP
+    Tc,
+}
+
+impl InputMode {
+    fn from_yaml(yaml: &Yaml) -> anyhow::Result<Self> {
+        Ok(match yaml.as_str() {
+            Some("auto") | None => InputMode::Auto,
+            Some("native") | Some("drv") => InputMode::Native,
+            Some("skb") | Some("generic") => InputMode::Skb,
+            Some("hw") | Some("offload") => InputMode::Hw,
+            Some("tc") => InputMode::Tc,
+            Some(mode) => bail!("Unknown XDP mode: {}", mode),
+        })
+    }
+
+    // Режимы в порядке попыток
+    fn flags(&self) -> Vec<(XdpFlags, &'static str)> {
+        match self {
+            InputMode::Auto => vec![(XdpFlags::DRV_MODE, "native"), (XdpFlags::SKB_MODE, "skb")],
+            InputMode::Native => vec![(XdpFlags::DRV_MODE, "native")],
+            InputMode::Skb => vec![(XdpFlags::SKB_MODE, "skb")],
+            InputMode::Hw => vec![(XdpFlags::HW_MODE, "hw")],
+            InputMode::Tc => vec![],
+        }
+    }
+}
+
+// Элемент `interfaces.input`: имя интерфейса или `{name, mode}`
+fn input_iface(yaml: &Yaml) -> anyhow::Result<(&str, InputMode)> {
+    match yaml.as_str() {
+        Some(iface) => Ok((iface, InputMode::Auto)),
+        None => Ok((
+            yaml["name"].as_str().unwrap(),
+            InputMode::from_yaml(&yaml["mode"])?,
+        )),
+    }
+}
+
+// Возвращает, в каком режиме и какая программа подключилась
+fn attach_input(
+    ebpf: &mut Ebpf,
+    iface: &str,
+    mode: InputMode,
+    frags: bool,
+) -> anyhow::Result<String> {
+    let mut last_err = anyhow::anyhow!("no XDP mode to try");
+    for (flags, mode_name) in mode.flags() {
+        if frags {
+            match attach_xdp(ebpf, XDP_FRAGS, iface, flags) {
+                Ok(_) => return Ok(format!("{}, frags", mode_name)),
+                Err(e) => warn!(
+                    "Failed to attach {} frags input: {}, iface: {}",
+                    mode_name, e, iface
+                ),
+            }
+        }
+        match attach_xdp(ebpf, XDP_LINEAR, iface, flags) {
+            Ok(_) => return Ok(format!("{}, linear", mode_name)),
+            Err(e) => {
+                warn!(
+                    "Failed to attach {} input: {}, iface: {}",
+                    mode_name, e, iface
+                );
+                last_err = e;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+fn attach_tc_ingress(ebpf: &mut Ebpf, iface: &str) -> anyhow::Result<String> {
+    // clsact может уже быть, например от tc_egress
+    if let Err(e) = qdisc_add_clsact(iface) {
+        debug!("Failed to add clsact qdisc: {}, iface: {}", e, iface);
+    }
+    let program: &mut SchedClassifier = ebpf.program_mut(TC_INGRESS).unwrap().try_into()?;
+    program.attach(iface, TcAttachType::Ingress)?;
+    Ok(String::from("tc"))
+}
+
+fn load_xdp(ebpf: &mut Ebpf, name: &str) -> anyhow::Result<()> {
+    let program: &mut Xdp = ebpf.program_mut(name).unwrap().try_into()?;
+    program.load()?;
+    Ok(())
+}
+
+fn attach_xdp(ebpf: &mut Ebpf, name: &str, iface: &str, flags: XdpFlags) -> anyhow::Result<()> {
+    let program: &mut Xdp = ebpf.program_mut(name).unwrap().try_into()?;
+    program.attach(iface, flags)?;
+    Ok(())
+}
+
 async fn force_in(ifname: &str) {
     let res = Command::new("ip")
         .args(["link", "set", "dev", ifname, "xdp", "off"])
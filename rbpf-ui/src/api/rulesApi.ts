@@ -15,4 +15,8 @@ export class Api {
     static deleteRule(id) {
         return axios.delete(`/api/v1/rules/${id}`);
     }
+
+    static getRuleStats(id) {
+        return axios.get(`/api/v1/rules/${id}/stats`);
+    }
 }
\ No newline at end of file
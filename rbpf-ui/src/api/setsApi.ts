@@ -0,0 +1,15 @@
+import axios from "axios";
+
+export class SetsApi {
+    static getSets() {
+        return axios.get("/api/v1/sets");
+    }
+
+    static updateSet(val) {
+        return axios.put(`/api/v1/sets/${val.name}`, val);
+    }
+
+    static deleteSet(name) {
+        return axios.delete(`/api/v1/sets/${name}`);
+    }
+}
@@ -1,6 +1,15 @@
 import { Module } from "vuex";
 import type { RootState } from "@/store"; //
 
+export interface InnerTuple {
+    tunnel: string;
+    protocol: number;
+    source_addr: string;
+    destination_addr: string;
+    source_port: number;
+    destination_port: number;
+}
+
 export interface LogMessage {
     traffic_type: string;
     protocol_version_type: string;
@@ -13,7 +22,10 @@ export interface LogMessage {
     rule_name: string;
     source_port: string;
     destination_port: string;
+    tcp_flags: number;
+    vlan_id: number;
     action: string;
+    inner: InnerTuple | null;
 }
 
 export interface LogsState {
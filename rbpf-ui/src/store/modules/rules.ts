@@ -15,6 +15,12 @@ export enum ProtocolVersionType {
 export enum ProtocolType {
     TCP = 0,
     UDP = 1,
+    ICMP = 2,
+    ICMPv6 = 3,
+    SCTP = 4,
+    DCCP = 5,
+    UDPLite = 6,
+    Other = 7,
 }
 
 export interface Rule {
@@ -36,6 +42,10 @@ export interface Rule {
     src_ip_low: number;
     tcp: boolean;
     udp: boolean;
+    icmp: boolean;
+    icmpv6: boolean;
+    icmp_type: number;
+    icmp_code: number;
     drop: boolean;
     ok: boolean;
     on: boolean;
@@ -45,6 +55,27 @@ export interface Rule {
     v6: boolean;
     v4: boolean;
     prior: number;
+    established: boolean;
+    source_set: string;
+    destination_set: string;
+    limit: boolean;
+    limit_rate: number;
+    limit_burst: number;
+    limit_bytes: boolean;
+    limit_per_source: boolean;
+    tcp_flags: number;
+    tcp_flags_mask: number;
+    reject: boolean;
+    monitor: boolean;
+    vlan_id: number;
+    ext_hdrs: number;
+    ext_hdrs_mask: number;
+    fragments: boolean;
+    ip_proto: number;
+    sctp: boolean;
+    dccp: boolean;
+    udplite: boolean;
+    tunnel: number;
     from_db: boolean;
 }
 
@@ -68,6 +99,10 @@ function withDefaults(rule: Partial<Rule>): Rule {
         src_ip_low: 0,
         tcp: false,
         udp: false,
+        icmp: false,
+        icmpv6: false,
+        icmp_type: 255,
+        icmp_code: 255,
         drop: false,
         ok: false,
         on: false,
@@ -77,6 +112,27 @@ function withDefaults(rule: Partial<Rule>): Rule {
         prior: 0,
         v6: false,
         v4: false,
+        established: false,
+        source_set: '',
+        destination_set: '',
+        limit: false,
+        limit_rate: 0,
+        limit_burst: 0,
+        limit_bytes: false,
+        limit_per_source: false,
+        tcp_flags: 0,
+        tcp_flags_mask: 0,
+        reject: false,
+        monitor: false,
+        vlan_id: 0,
+        ext_hdrs: 0,
+        ext_hdrs_mask: 0,
+        fragments: false,
+        ip_proto: 0,
+        sctp: false,
+        dccp: false,
+        udplite: false,
+        tunnel: 0,
         from_db: true,
         ...rule,
     };
@@ -188,6 +188,7 @@ pub fn prepare_package_contents() -> Result<()> {
 
     fs_ext::copy_dir_recursive("./contrib/settings", config_dir.join("settings"))?;
     fs_ext::copy_dir_recursive("./contrib/rules", config_dir.join("rules"))?;
+    fs_ext::copy_dir_recursive("./contrib/sets", config_dir.join("sets"))?;
     fs_ext::copy_dir_recursive("./contrib/migrations", config_dir.join("migrations"))?;
 
     let systemd_dir = Path::new("./rbpf-build/opt/rbpf/systemd/");